@@ -0,0 +1,63 @@
+//! Throughput of each resampling filter when a large cover is scaled to a Discord thumbnail.
+//!
+//! Run with `cargo bench --bench resize`.
+
+use clap::ValueEnum;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::{DynamicImage, ImageBuffer, Rgb};
+use rich_thumbnail_uploader::cli::{ResampleFilter, ResizeMode};
+use rich_thumbnail_uploader::image_processor::{
+    resize, Background, Dimensions, Resampling, Upscale,
+};
+
+/// Side of the source image, about the size of a high resolution scan
+const COVER_SIZE: u32 = 3000;
+
+/// Gradients with a fine grid on top, so filters have edges to work on
+fn cover() -> DynamicImage {
+    DynamicImage::ImageRgb8(ImageBuffer::from_fn(COVER_SIZE, COVER_SIZE, |x, y| {
+        let grid = if x % 16 < 2 || y % 16 < 2 { 0 } else { 255 };
+        Rgb([
+            (x * 255 / COVER_SIZE) as u8,
+            (y * 255 / COVER_SIZE) as u8,
+            grid,
+        ])
+    }))
+}
+
+fn filters(c: &mut Criterion) {
+    let img = cover();
+    let size = Dimensions::square(256);
+
+    let mut group = c.benchmark_group("resize_3000_to_256");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((COVER_SIZE * COVER_SIZE) as u64));
+
+    for filter in ResampleFilter::value_variants() {
+        for linear in [false, true] {
+            let resampling = Resampling {
+                filter: filter.to_filter_type(),
+                linear,
+            };
+            let light = if linear { "linear" } else { "gamma" };
+
+            group.bench_function(BenchmarkId::new(filter.to_string(), light), |b| {
+                b.iter(|| {
+                    resize(
+                        &img,
+                        size,
+                        ResizeMode::Fit,
+                        Background::default(),
+                        resampling,
+                        Upscale::Allow,
+                    )
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, filters);
+criterion_main!(benches);
@@ -0,0 +1,524 @@
+use image::ImageFormat;
+use lofty::config::ParseOptions;
+use lofty::file::{FileType, TaggedFileExt};
+use lofty::picture::{Picture, PictureType};
+use lofty::probe::Probe;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::errors::AppError;
+use crate::image_processor::DecodeLimits;
+
+/// Embedded picture types in order of preference, anything else ranks after these
+static PICTURE_PREFERENCE: [PictureType; 5] = [
+    PictureType::CoverFront,
+    PictureType::Other,
+    PictureType::Media,
+    PictureType::Leaflet,
+    PictureType::CoverBack,
+];
+
+/// Sidecar image names searched in the album folder, `*` matches any run of characters
+pub static DEFAULT_FOLDER_ART: [&str; 4] = ["cover", "folder", "front", "albumart*"];
+
+/// Whether `path` looks like an audio file that may carry embedded artwork
+pub fn is_audio_file(path: &Path) -> bool {
+    FileType::from_path(path).is_some()
+}
+
+/// Reads the image to thumbnail from `path`.
+///
+/// Audio files are searched for embedded artwork first, and fall back to sidecar images
+/// in the same folder, also when their tags cannot be read. Directories are searched for sidecar
+/// images only. Image files and embedded pictures larger than the limits allow are refused
+/// before they are read or copied.
+pub fn read_source(
+    path: &Path,
+    folder_art: &[String],
+    limits: &DecodeLimits,
+) -> Result<Vec<u8>, AppError> {
+    let mut tag_error = None;
+    let folder = if path.is_dir() {
+        path
+    } else if is_audio_file(path) {
+        match extract_embedded_art(path, limits) {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => {}
+            // a truncated or unsupported tag should not hide the art next to the track
+            Err(e @ AppError::Artwork(_)) => tag_error = Some(e),
+            Err(e) => return Err(e),
+        }
+
+        path.parent().unwrap_or(Path::new("."))
+    } else {
+        return read_image(path, limits);
+    };
+
+    match find_folder_art(folder, folder_art)? {
+        Some(image) => read_image(&image, limits),
+        // without folder art the broken tag explains more than a missing cover
+        None => Err(match tag_error {
+            Some(e) => e,
+            None => AppError::NoArtwork(path.to_string_lossy().to_string()),
+        }),
+    }
+}
+
+/// Reads an image file, refusing one over the size limit without reading it
+fn read_image(path: &Path, limits: &DecodeLimits) -> Result<Vec<u8>, AppError> {
+    limits.check_file_size(fs::metadata(path)?.len())?;
+    Ok(fs::read(path)?)
+}
+
+/// Finds the first image in `dir` whose name matches one of `patterns`, in priority order.
+/// Matching ignores case and the file extension.
+pub fn find_folder_art(dir: &Path, patterns: &[String]) -> Result<Option<PathBuf>, AppError> {
+    let mut images: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && ImageFormat::from_path(path).is_ok())
+        .collect();
+
+    // keep the choice stable when several files match the same pattern
+    images.sort();
+
+    for pattern in patterns {
+        let pattern = pattern.to_lowercase();
+        let found = images.iter().find(|image| {
+            image
+                .file_stem()
+                .map(|stem| wildcard_match(&pattern, &stem.to_string_lossy().to_lowercase()))
+                .unwrap_or(false)
+        });
+
+        if let Some(found) = found {
+            return Ok(Some(found.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+
+            // try every possible length for the run matched by `*`
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(name.len()))
+                .any(|i| wildcard_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// Returns the most relevant picture embedded in the tags of an audio file, if any.
+/// A picture over the size limit is refused before it is copied out of the tag.
+pub fn extract_embedded_art(
+    path: &Path,
+    limits: &DecodeLimits,
+) -> Result<Option<Vec<u8>>, AppError> {
+    // audio properties are not needed, only the tags
+    let tagged_file = Probe::open(path)
+        .and_then(|probe| {
+            probe
+                .options(ParseOptions::new().read_properties(false))
+                .read()
+        })
+        .map_err(|e| AppError::Artwork(e.to_string()))?;
+
+    let picture = tagged_file
+        .tags()
+        .iter()
+        .flat_map(|tag| tag.pictures())
+        .min_by_key(|picture| preference(picture));
+
+    match picture {
+        Some(picture) => {
+            limits.check_file_size(picture.data().len() as u64)?;
+            Ok(Some(picture.data().to_vec()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn preference(picture: &Picture) -> usize {
+    PICTURE_PREFERENCE
+        .iter()
+        .position(|pic_type| *pic_type == picture.pic_type())
+        .unwrap_or(PICTURE_PREFERENCE.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, ImageFormat, Rgb};
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn png(color: [u8; 3]) -> Vec<u8> {
+        let img = ImageBuffer::from_pixel(8, 8, Rgb(color));
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, ImageFormat::Png).unwrap();
+        buf.into_inner()
+    }
+
+    /// FLAC METADATA_BLOCK_PICTURE payload
+    fn flac_picture(pic_type: u32, data: &[u8]) -> Vec<u8> {
+        let mime = b"image/png";
+        let mut block = Vec::new();
+        block.extend(pic_type.to_be_bytes());
+        block.extend((mime.len() as u32).to_be_bytes());
+        block.extend(mime);
+        block.extend(0u32.to_be_bytes()); // description
+        block.extend([0; 16]); // width, height, depth, colors
+        block.extend((data.len() as u32).to_be_bytes());
+        block.extend(data);
+        block
+    }
+
+    /// Minimal FLAC stream: STREAMINFO followed by the given picture blocks
+    fn flac(pictures: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"fLaC".to_vec();
+        let mut streaminfo = vec![0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0];
+        // 44100 Hz, 2 channels, 16 bits per sample, 0 samples
+        streaminfo.extend([0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+        streaminfo.extend([0; 16]);
+
+        file.push(if pictures.is_empty() { 0x80 } else { 0 });
+        file.extend(&(streaminfo.len() as u32).to_be_bytes()[1..]);
+        file.extend(streaminfo);
+
+        for (i, picture) in pictures.iter().enumerate() {
+            let last = if i == pictures.len() - 1 { 0x80 } else { 0 };
+            file.push(last | 6);
+            file.extend(&(picture.len() as u32).to_be_bytes()[1..]);
+            file.extend(picture);
+        }
+
+        file
+    }
+
+    /// Minimal MP3: an ID3v2.3 tag with a single APIC frame and one silent MPEG frame
+    fn mp3(pic_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut apic = vec![0];
+        apic.extend(b"image/png\0");
+        apic.push(pic_type);
+        apic.push(0); // description
+        apic.extend(data);
+
+        let mut frame = b"APIC".to_vec();
+        frame.extend((apic.len() as u32).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(apic);
+
+        let size = frame.len() as u32;
+        let mut file = b"ID3\x03\x00\x00".to_vec();
+        // synchsafe tag size
+        file.extend([
+            ((size >> 21) & 0x7F) as u8,
+            ((size >> 14) & 0x7F) as u8,
+            ((size >> 7) & 0x7F) as u8,
+            (size & 0x7F) as u8,
+        ]);
+        file.extend(frame);
+
+        // MPEG-1 Layer III, 128 kbps, 44100 Hz
+        file.extend([0xFF, 0xFB, 0x90, 0x00]);
+        file.extend([0; 413]);
+        file
+    }
+
+    /// MP4 box: big-endian size, four character type, contents
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(kind);
+        atom.extend(body);
+        atom
+    }
+
+    /// Minimal M4A: `ftyp` and a `moov.udta.meta.ilst` holding one `covr` data atom per image
+    fn m4a(covers: &[Vec<u8>]) -> Vec<u8> {
+        let covr: Vec<u8> = covers
+            .iter()
+            .flat_map(|cover| {
+                let mut data = 14u32.to_be_bytes().to_vec(); // well-known type PNG
+                data.extend([0; 4]); // locale
+                data.extend(cover);
+                atom(b"data", &data)
+            })
+            .collect();
+
+        let mut meta = vec![0; 4]; // full box version and flags
+        meta.extend(atom(b"ilst", &atom(b"covr", &covr)));
+
+        let mut file = atom(b"ftyp", b"M4A \0\0\0\0M4A isom");
+        file.extend(atom(b"moov", &atom(b"udta", &atom(b"meta", &meta))));
+        file
+    }
+
+    fn base64(data: &[u8]) -> String {
+        let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        data.chunks(3)
+            .flat_map(|chunk| {
+                let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+                    bits | (*byte as u32) << (16 - 8 * i)
+                });
+                (0..4).map(move |i| {
+                    if i <= chunk.len() {
+                        alphabet[(bits >> (18 - 6 * i) & 0x3F) as usize] as char
+                    } else {
+                        '='
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Ogg page holding whole packets, the checksum is left at zero
+    fn ogg_page(sequence: u32, first: bool, packet: &[u8]) -> Vec<u8> {
+        let mut lacing = vec![255; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut page = b"OggS\0".to_vec();
+        page.push(if first { 2 } else { 0 });
+        page.extend([0; 8]); // granule position
+        page.extend(1u32.to_le_bytes()); // stream serial number
+        page.extend(sequence.to_le_bytes());
+        page.extend([0; 4]); // checksum
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(packet);
+        page
+    }
+
+    /// Minimal Ogg Opus: an `OpusHead` page and an `OpusTags` page with one
+    /// METADATA_BLOCK_PICTURE comment per picture block
+    fn opus(pictures: &[Vec<u8>]) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend([1, 2, 0x38, 0x01]); // version, channels, pre-skip
+        head.extend(48000u32.to_le_bytes());
+        head.extend([0, 0, 0]); // gain, mapping family
+
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend(0u32.to_le_bytes()); // vendor
+        tags.extend((pictures.len() as u32).to_le_bytes());
+        for picture in pictures {
+            let comment = format!("METADATA_BLOCK_PICTURE={}", base64(picture));
+            tags.extend((comment.len() as u32).to_le_bytes());
+            tags.extend(comment.as_bytes());
+        }
+
+        let mut file = ogg_page(0, true, &head);
+        file.extend(ogg_page(1, false, &tags));
+        file
+    }
+
+    fn default_patterns() -> Vec<String> {
+        DEFAULT_FOLDER_ART.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read_source_plain_image() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("cover.png");
+        fs::write(&path, png([1, 2, 3])).unwrap();
+
+        assert!(!is_audio_file(&path));
+        assert_eq!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            png([1, 2, 3])
+        );
+
+        let limits = DecodeLimits {
+            max_file_size: 10,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            read_source(&path, &default_patterns(), &limits).unwrap_err(),
+            AppError::DecodeLimit(_)
+        ));
+    }
+
+    #[test]
+    fn test_extract_flac_prefers_front_cover() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.flac");
+        let back = png([0, 0, 255]);
+        let front = png([255, 0, 0]);
+
+        // back cover (4) comes first, front cover (3) must still win
+        fs::write(
+            &path,
+            flac(&[flac_picture(4, &back), flac_picture(3, &front)]),
+        )
+        .unwrap();
+
+        assert!(is_audio_file(&path));
+        assert_eq!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            front
+        );
+    }
+
+    #[test]
+    fn test_extract_oversized_picture() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.flac");
+        let cover = png([255, 0, 0]);
+        fs::write(&path, flac(&[flac_picture(3, &cover)])).unwrap();
+        // folder art is no way around the limit
+        fs::write(temp_dir.path().join("cover.png"), png([0, 0, 0])).unwrap();
+
+        let limits = DecodeLimits {
+            max_file_size: cover.len() as u64 - 1,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            read_source(&path, &default_patterns(), &limits).unwrap_err(),
+            AppError::DecodeLimit(_)
+        ));
+    }
+
+    #[test]
+    fn test_extract_mp3_apic() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.mp3");
+        let cover = png([0, 255, 0]);
+        fs::write(&path, mp3(3, &cover)).unwrap();
+
+        assert_eq!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            cover
+        );
+    }
+
+    #[test]
+    fn test_extract_mp4_covr() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.m4a");
+        let first = png([0, 0, 255]);
+        let second = png([255, 255, 0]);
+        fs::write(&path, m4a(&[first.clone(), second])).unwrap();
+
+        // covr carries no picture types, the first image is the cover
+        assert!(is_audio_file(&path));
+        assert_eq!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            first
+        );
+    }
+
+    #[test]
+    fn test_extract_opus_prefers_front_cover() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.opus");
+        let back = png([0, 0, 255]);
+        let front = png([255, 0, 0]);
+
+        // back cover (4) comes first, front cover (3) must still win
+        fs::write(
+            &path,
+            opus(&[flac_picture(4, &back), flac_picture(3, &front)]),
+        )
+        .unwrap();
+
+        assert!(is_audio_file(&path));
+        assert_eq!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            front
+        );
+    }
+
+    #[test]
+    fn test_extract_without_artwork() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("track.flac");
+        fs::write(&path, flac(&[])).unwrap();
+
+        assert!(extract_embedded_art(&path, &DecodeLimits::default())
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            read_source(&path, &default_patterns(), &DecodeLimits::default()).unwrap_err(),
+            AppError::NoArtwork(_)
+        ));
+    }
+
+    #[test]
+    fn test_read_source_corrupt_tags_fall_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let track = temp_dir.path().join("track.flac");
+        // the STREAMINFO block is cut short
+        fs::write(&track, b"fLaC\x00\x00\x22\x10").unwrap();
+
+        assert!(matches!(
+            read_source(&track, &default_patterns(), &DecodeLimits::default()).unwrap_err(),
+            AppError::Artwork(_)
+        ));
+
+        fs::write(temp_dir.path().join("folder.jpg"), png([7, 7, 7])).unwrap();
+        assert_eq!(
+            read_source(&track, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            png([7, 7, 7])
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("cover", "cover"));
+        assert!(!wildcard_match("cover", "covers"));
+        assert!(wildcard_match("albumart*", "albumart"));
+        assert!(wildcard_match("albumart*", "albumartsmall"));
+        assert!(wildcard_match("*front*", "cd front scan"));
+        assert!(!wildcard_match("albumart*", "art"));
+    }
+
+    #[test]
+    fn test_find_folder_art_priority_and_case() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("AlbumArtSmall.JPG"), png([0, 0, 0])).unwrap();
+        fs::write(temp_dir.path().join("Folder.png"), png([0, 0, 0])).unwrap();
+        fs::write(temp_dir.path().join("cover.txt"), b"not an image").unwrap();
+
+        let found = find_folder_art(temp_dir.path(), &default_patterns()).unwrap();
+        assert_eq!(found, Some(temp_dir.path().join("Folder.png")));
+
+        let patterns = vec!["albumart*".to_string(), "folder".to_string()];
+        let found = find_folder_art(temp_dir.path(), &patterns).unwrap();
+        assert_eq!(found, Some(temp_dir.path().join("AlbumArtSmall.JPG")));
+
+        let patterns = vec!["front".to_string()];
+        assert!(find_folder_art(temp_dir.path(), &patterns)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_source_folder_art_fallback() {
+        let temp_dir = TempDir::new().unwrap();
+        let track = temp_dir.path().join("01 - track.flac");
+        fs::write(&track, flac(&[])).unwrap();
+        fs::write(temp_dir.path().join("folder.jpg"), png([9, 9, 9])).unwrap();
+
+        // audio without embedded art
+        assert_eq!(
+            read_source(&track, &default_patterns(), &DecodeLimits::default()).unwrap(),
+            png([9, 9, 9])
+        );
+
+        // the album directory itself
+        assert_eq!(
+            read_source(
+                temp_dir.path(),
+                &default_patterns(),
+                &DecodeLimits::default()
+            )
+            .unwrap(),
+            png([9, 9, 9])
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::output::UploadRecord;
+use crate::uploaders::UploadServiceIdentifier;
+
+static CACHE_FILENAME: &str = "cache.json";
+
+/// The settings that shape an uploaded thumbnail, serialized into the cache key
+#[derive(Serialize)]
+struct KeySettings {
+    width: u32,
+    height: u32,
+    format: &'static str,
+    quality: u8,
+    webp: &'static str,
+    png_compression: &'static str,
+    png_filter: &'static str,
+    resize: &'static str,
+    filter: &'static str,
+    linear: bool,
+    upscale: String,
+    min_source_size: Option<String>,
+    max_bytes: Option<u64>,
+    frame: u32,
+    animate: bool,
+    background: String,
+    alpha: String,
+    services: Vec<String>,
+    // sorted, a `HashMap` would serialize in a different order on every run
+    endpoints: BTreeMap<String, String>,
+    service_formats: BTreeMap<String, &'static str>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub record: UploadRecord,
+    pub created: u64,
+}
+
+impl CacheEntry {
+    pub fn is_expired(&self, ttl: Duration, now: u64) -> bool {
+        now.saturating_sub(self.created) >= ttl.as_secs()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CacheFile {
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// On-disk map from source image + processing parameters to an uploaded url
+pub struct UploadCache {
+    path: PathBuf,
+    file: CacheFile,
+}
+
+impl UploadCache {
+    /// Default location of the cache file, inside the platform cache directory
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(CACHE_FILENAME))
+    }
+
+    /// Opens the cache at `path`. A missing or unreadable file yields an empty cache
+    pub fn open(path: &Path) -> Self {
+        let file = fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        UploadCache {
+            path: path.to_path_buf(),
+            file,
+        }
+    }
+
+    /// Hashes the source bytes together with everything that affects the uploaded thumbnail
+    pub fn key(source: &[u8], config: &Config) -> String {
+        let settings = KeySettings {
+            width: config.image_dimensions.0,
+            height: config.image_dimensions.1,
+            format: config.image_format.as_str(),
+            quality: config.encoding.quality,
+            webp: config.encoding.webp.as_str(),
+            png_compression: config.encoding.png_compression.as_str(),
+            png_filter: config.encoding.png_filter.as_str(),
+            resize: config.resize_mode.as_str(),
+            filter: config.filter.as_str(),
+            linear: config.linear,
+            upscale: config.upscale.to_string(),
+            min_source_size: config.min_source_size.map(|min| min.to_string()),
+            max_bytes: config.max_bytes,
+            frame: config.frame,
+            animate: config.animate,
+            background: config.background.to_string(),
+            alpha: config.alpha.to_string(),
+            services: config.services.iter().map(|s| s.to_string()).collect(),
+            endpoints: config
+                .endpoints
+                .iter()
+                .map(|(service, endpoint)| (service.to_string(), endpoint.clone()))
+                .collect(),
+            service_formats: config
+                .service_formats
+                .iter()
+                .map(|(service, format)| (service.to_string(), format.as_str()))
+                .collect(),
+        };
+
+        // the source is length-prefixed and the settings are JSON, so no two inputs run together
+        let mut hasher = Sha256::new();
+        hasher.update((source.len() as u64).to_le_bytes());
+        hasher.update(source);
+        hasher.update(serde_json::to_vec(&settings).expect("plain data always serializes"));
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Returns the entry for `key` if it is younger than the ttl of the service that holds it
+    pub fn get(
+        &self,
+        key: &str,
+        ttl: impl Fn(UploadServiceIdentifier) -> Duration,
+    ) -> Option<&CacheEntry> {
+        self.file
+            .entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(ttl(entry.record.service), now()))
+    }
+
+    pub fn insert(&mut self, key: String, record: UploadRecord) {
+        self.file.entries.insert(
+            key,
+            CacheEntry {
+                record,
+                created: now(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.file.entries.remove(key);
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let data = serde_json::to_vec(&self.file).map_err(|e| AppError::Cache(e.to_string()))?;
+        fs::write(&self.path, data)?;
+
+        Ok(())
+    }
+}
+
+/// Checks that a previously uploaded link still serves an image
+pub async fn is_link_alive(url: &str, client: &reqwest::Client) -> bool {
+    let response = match client.head(url).send().await {
+        // some hosts do not implement HEAD, retry with a plain GET
+        Ok(response) if response.status() == reqwest::StatusCode::METHOD_NOT_ALLOWED => {
+            client.get(url).send().await
+        }
+        response => response,
+    };
+
+    match response {
+        // imgur redirects deleted images to a placeholder instead of returning 404
+        Ok(response) => {
+            response.status().is_success() && !response.url().path().ends_with("/removed.png")
+        }
+        Err(_) => false,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{Cli, SupportedImageFormat};
+    use crate::config_file::ConfigFile;
+    use crate::image_processor::Dimensions;
+    use clap::Parser;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempfile::TempDir;
+
+    static TTL: Duration = Duration::from_secs(60);
+
+    fn ttl(_: UploadServiceIdentifier) -> Duration {
+        TTL
+    }
+
+    fn record(url: &str, service: UploadServiceIdentifier) -> UploadRecord {
+        UploadRecord {
+            url: url.to_string(),
+            service,
+            deletehash: None,
+            width: 256,
+            height: 256,
+            bytes: 1000,
+            format: "png".to_string(),
+            quality: None,
+            sha256: String::new(),
+            cached: false,
+            profile: None,
+        }
+    }
+
+    fn test_config(dims: &str, format: &str) -> Config {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox",
+            "--dims",
+            dims,
+            "--format",
+            format,
+        ]);
+
+        Config::from_sources(&cli, &ConfigFile::default(), |_| None).unwrap()
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("nested").join(CACHE_FILENAME);
+
+        let mut cache = UploadCache::open(&path);
+        assert!(cache.get("key", ttl).is_none());
+
+        cache.insert(
+            "key".to_string(),
+            record(
+                "https://files.catbox.moe/abc.png",
+                UploadServiceIdentifier::Catbox,
+            ),
+        );
+        cache.save().unwrap();
+
+        let cache = UploadCache::open(&path);
+        let entry = cache.get("key", ttl).unwrap();
+        assert_eq!(entry.record.url, "https://files.catbox.moe/abc.png");
+        assert_eq!(entry.record.service, UploadServiceIdentifier::Catbox);
+        assert_eq!(entry.record.width, 256);
+    }
+
+    #[test]
+    fn test_cache_ignores_run_fields() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(CACHE_FILENAME);
+
+        let mut cache = UploadCache::open(&path);
+        cache.insert(
+            "key".to_string(),
+            UploadRecord {
+                cached: true,
+                profile: Some("discord".to_string()),
+                ..record(
+                    "https://files.catbox.moe/abc.png",
+                    UploadServiceIdentifier::Catbox,
+                )
+            },
+        );
+        cache.save().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("cached"));
+        assert!(!text.contains("profile"));
+
+        let cache = UploadCache::open(&path);
+        let entry = cache.get("key", ttl).unwrap();
+        assert!(!entry.record.cached);
+        assert_eq!(entry.record.profile, None);
+    }
+
+    #[test]
+    fn test_cache_entry_expiry() {
+        let entry = CacheEntry {
+            record: record(
+                "https://i.imgur.com/abc.png",
+                UploadServiceIdentifier::Imgur,
+            ),
+            created: 1000,
+        };
+
+        assert!(!entry.is_expired(TTL, 1000));
+        assert!(!entry.is_expired(TTL, 1059));
+        assert!(entry.is_expired(TTL, 1060));
+        assert!(entry.is_expired(Duration::ZERO, 1000));
+    }
+
+    #[test]
+    fn test_cache_get_skips_expired() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cache = UploadCache::open(&temp_dir.path().join(CACHE_FILENAME));
+
+        cache.insert(
+            "key".to_string(),
+            record(
+                "https://i.imgur.com/abc.png",
+                UploadServiceIdentifier::Imgur,
+            ),
+        );
+        assert!(cache.get("key", ttl).is_some());
+        assert!(cache.get("key", |_| Duration::ZERO).is_none());
+
+        cache.remove("key");
+        assert!(cache.get("key", ttl).is_none());
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::new()
+    }
+
+    /// Serves the given raw HTTP responses, one per connection, and returns the base url
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_is_link_alive() {
+        let url = serve(vec!["HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]);
+        assert!(is_link_alive(&format!("{}/abc.png", url), &client()).await);
+
+        let url = serve(vec!["HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"]);
+        assert!(!is_link_alive(&format!("{}/abc.png", url), &client()).await);
+    }
+
+    #[tokio::test]
+    async fn test_is_link_alive_head_not_allowed() {
+        let url = serve(vec![
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        assert!(is_link_alive(&format!("{}/abc.png", url), &client()).await);
+    }
+
+    #[tokio::test]
+    async fn test_is_link_alive_removed_redirect() {
+        let url = serve(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /removed.png\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        assert!(!is_link_alive(&format!("{}/abc.png", url), &client()).await);
+    }
+
+    #[tokio::test]
+    async fn test_is_link_alive_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/abc.png", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(!is_link_alive(&url, &client()).await);
+    }
+
+    #[test]
+    fn test_cache_open_corrupt_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(CACHE_FILENAME);
+        fs::write(&path, b"not json").unwrap();
+
+        let cache = UploadCache::open(&path);
+        assert!(cache.get("key", ttl).is_none());
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_parameters() {
+        let png = test_config("256", "png");
+        assert_eq!(png.image_format, SupportedImageFormat::Png);
+
+        let base = UploadCache::key(b"image", &png);
+        assert_eq!(base, UploadCache::key(b"image", &png));
+        assert_ne!(base, UploadCache::key(b"other", &png));
+        assert_ne!(base, UploadCache::key(b"image", &test_config("512", "png")));
+        assert_ne!(
+            base,
+            UploadCache::key(b"image", &test_config("256", "webp"))
+        );
+
+        let mut budget = test_config("256", "png");
+        budget.max_bytes = Some(100_000);
+        assert_ne!(base, UploadCache::key(b"image", &budget));
+
+        let mut endpoint = test_config("256", "png");
+        endpoint.endpoints.insert(
+            UploadServiceIdentifier::Catbox,
+            "https://example.com/api.php".to_string(),
+        );
+        assert_ne!(base, UploadCache::key(b"image", &endpoint));
+
+        let mut service_format = test_config("256", "png");
+        service_format
+            .service_formats
+            .insert(UploadServiceIdentifier::Catbox, SupportedImageFormat::Webp);
+        assert_ne!(base, UploadCache::key(b"image", &service_format));
+
+        // a setting that is unset is not mistaken for one that is set to the same bytes
+        let mut small = test_config("256", "png");
+        small.min_source_size = Some(Dimensions::square(100));
+        assert_ne!(
+            UploadCache::key(b"image", &small),
+            UploadCache::key(b"image", &budget)
+        );
+    }
+}
@@ -1,10 +1,13 @@
 use clap::{Parser, ValueEnum};
-use image::ImageFormat;
+use image::codecs::png::{CompressionType, FilterType};
+use image::{imageops, ImageFormat};
+use serde::Deserialize;
 use std::io::{self, BufRead};
 use std::path::PathBuf;
 use std::process;
 
 use crate::errors::AppError;
+use crate::image_processor::{Alpha, Background, Dimensions, Upscale};
 use crate::uploaders::UploadServiceIdentifier;
 
 #[derive(Parser)]
@@ -16,60 +19,408 @@ use crate::uploaders::UploadServiceIdentifier;
     override_usage = concat!(env!("CARGO_PKG_NAME"), " [OPTIONS]")
 )]
 pub struct Cli {
-    /// Dimensions to crop the image to
-    #[arg(short, long, default_value = "256")]
-    pub dims: u32,
+    /// Path to a TOML config file, values given on the command line take precedence
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the merged settings and where each one came from, then exit
+    #[arg(long)]
+    pub show_config: bool,
+
+    /// Profile from the config file to apply, e.g. discord
+    #[arg(short, long)]
+    pub profile: Option<String>,
+
+    /// Size of the thumbnail box, 256 for a square or 512x256 [default: 256]
+    #[arg(short, long)]
+    pub dims: Option<Dimensions>,
+
+    /// Width of the thumbnail box, overrides --dims
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// Height of the thumbnail box, overrides --dims
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// How the image is fitted into the thumbnail [default: fit]
+    #[arg(short, long, value_enum)]
+    pub resize: Option<ResizeMode>,
+
+    /// Resampling filter used to scale the image [default: catmull-rom]
+    #[arg(long, value_enum)]
+    pub filter: Option<ResampleFilter>,
+
+    /// Resample in linear light, slower but keeps dark covers and fine text clean
+    #[arg(long)]
+    pub linear: bool,
 
-    /// Upload service to use
-    #[arg(short, long, value_enum, default_value_t = UploadServiceIdentifier::Imgur)]
-    pub service: UploadServiceIdentifier,
+    /// How far a small source may be scaled up: never, allow or allow-up-to-2x [default: allow]
+    #[arg(long)]
+    pub upscale: Option<Upscale>,
 
-    /// Output format
-    #[arg(short, long, value_enum, default_value_t = OutputFormat::Url)]
-    pub output: OutputFormat,
+    /// Reject sources smaller than this, 300 or 300x200, instead of thumbnailing them
+    #[arg(long)]
+    pub min_source_size: Option<Dimensions>,
+
+    /// Frame of an animated source to make a still thumbnail of, counting from 1 [default: 1]
+    #[arg(long)]
+    pub frame: Option<u32>,
+
+    /// Keep animated sources animated, every frame is resized (GIF and WebP thumbnails)
+    #[arg(long)]
+    pub animate: bool,
+
+    /// Border colour for the pad mode, #rrggbb, #rrggbbaa or blur [default: #000000]
+    #[arg(short, long)]
+    pub background: Option<Background>,
+
+    /// What becomes of transparency: keep, auto or a #rrggbb colour to flatten onto [default: keep]
+    #[arg(long)]
+    pub alpha: Option<Alpha>,
+
+    /// Upload services to try in order, e.g. imgur,catbox [default: imgur]
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    pub service: Option<Vec<UploadServiceIdentifier>>,
+
+    /// Output format [default: url]
+    #[arg(short, long, value_enum)]
+    pub output: Option<OutputFormat>,
+
+    /// Template for the template output, e.g. '![cover]({url})'
+    #[arg(short, long)]
+    pub template: Option<String>,
 
     /// Optional uid (overrides provided client id for imgur / sets user hash for catbox)
     #[arg(short, long)]
     pub uid: Option<String>,
 
-    /// Preffered image format, only affects catbox
-    #[arg(short, long, value_enum, default_value_t = SupportedImageFormat::Png)]
-    pub format: SupportedImageFormat,
+    /// Image format of the thumbnail [default: png]
+    #[arg(short, long, value_enum)]
+    pub format: Option<SupportedImageFormat>,
+
+    /// Quality of lossy thumbnails (JPEG, AVIF, lossy WebP), 1-100 [default: 85]
+    #[arg(short, long)]
+    pub quality: Option<u8>,
+
+    /// Largest thumbnail in bytes, quality and then size are lowered until it fits
+    #[arg(long)]
+    pub max_bytes: Option<u64>,
+
+    /// Whether WebP thumbnails are lossless or lossy [default: lossless]
+    #[arg(long, value_enum)]
+    pub webp: Option<WebpMode>,
+
+    /// Compression effort for PNG thumbnails [default: fast]
+    #[arg(long, value_enum)]
+    pub png_compression: Option<PngCompression>,
+
+    /// Row filter for PNG thumbnails [default: adaptive]
+    #[arg(long, value_enum)]
+    pub png_filter: Option<PngFilter>,
+
+    /// Path to the upload cache file (defaults to the platform cache directory)
+    #[arg(long)]
+    pub cache: Option<PathBuf>,
+
+    /// Always upload, without reading or writing the upload cache
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Seconds a cached link stays valid (defaults to a per-service value)
+    #[arg(long)]
+    pub cache_ttl: Option<u64>,
+
+    /// Check that a cached link is still reachable before using it
+    #[arg(long)]
+    pub revalidate: bool,
+
+    /// How many times a failed upload is retried per service [default: 2]
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// Delay in milliseconds before the first retry, doubled for every following one [default: 500]
+    #[arg(long)]
+    pub retry_delay: Option<u64>,
+
+    /// Seconds to wait for a connection to be established [default: 10]
+    #[arg(long)]
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds a single request may take [default: 30]
+    #[arg(long)]
+    pub request_timeout: Option<u64>,
+
+    /// Seconds the whole run may take, including retries and fallback services [default: 60]
+    #[arg(long)]
+    pub timeout: Option<u64>,
+
+    /// Largest source image in bytes, bigger files are refused before they are read [default: 67108864]
+    #[arg(long)]
+    pub max_file_size: Option<u64>,
+
+    /// Largest source width and height, 16384 or 20000x10000, checked before decoding [default: 16384]
+    #[arg(long)]
+    pub max_source_dims: Option<Dimensions>,
+
+    /// Most memory in bytes decoding a source may take [default: 536870912]
+    #[arg(long)]
+    pub max_alloc: Option<u64>,
+
+    /// Sidecar image names to look for next to the track, in order of priority
+    /// [default: cover,folder,front,albumart*]
+    #[arg(long, value_delimiter = ',')]
+    pub folder_art: Option<Vec<String>>,
 }
 
 impl std::fmt::Display for UploadServiceIdentifier {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_string())
+        match self {
+            UploadServiceIdentifier::Imgur => write!(f, "imgur"),
+            UploadServiceIdentifier::Catbox => write!(f, "catbox"),
+        }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
 pub enum OutputFormat {
     Url,
+    Json,
+    Template,
+}
+
+impl OutputFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputFormat::Url => "url",
+            OutputFormat::Json => "json",
+            OutputFormat::Template => "template",
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeMode {
+    /// Keep the aspect ratio, the longest side is the requested size
+    Fit,
+    /// Crop the centre to the box's aspect ratio, then scale it
+    Fill,
+    /// Like fill, but the crop follows the most detailed part of the image
+    Smart,
+    /// Keep the aspect ratio and letterbox the rest of the square
+    Pad,
+    /// Scale to a square, ignoring the aspect ratio
+    Stretch,
+}
+
+impl ResizeMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResizeMode::Fit => "fit",
+            ResizeMode::Fill => "fill",
+            ResizeMode::Smart => "smart",
+            ResizeMode::Pad => "pad",
+            ResizeMode::Stretch => "stretch",
+        }
+    }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+impl std::fmt::Display for ResizeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResampleFilter {
+    /// Fastest, blocky
+    Nearest,
+    /// Bilinear
+    Triangle,
+    /// Bicubic, sharp without much ringing
+    CatmullRom,
+    /// Soft
+    Gaussian,
+    /// Sharpest, slowest
+    Lanczos3,
+}
+
+impl ResampleFilter {
+    pub fn to_filter_type(self) -> imageops::FilterType {
+        match self {
+            ResampleFilter::Nearest => imageops::FilterType::Nearest,
+            ResampleFilter::Triangle => imageops::FilterType::Triangle,
+            ResampleFilter::CatmullRom => imageops::FilterType::CatmullRom,
+            ResampleFilter::Gaussian => imageops::FilterType::Gaussian,
+            ResampleFilter::Lanczos3 => imageops::FilterType::Lanczos3,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResampleFilter::Nearest => "nearest",
+            ResampleFilter::Triangle => "triangle",
+            ResampleFilter::CatmullRom => "catmull-rom",
+            ResampleFilter::Gaussian => "gaussian",
+            ResampleFilter::Lanczos3 => "lanczos3",
+        }
+    }
+}
+
+impl std::fmt::Display for ResampleFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
 pub enum SupportedImageFormat {
     Png,
     Webp,
+    #[value(alias = "jpg")]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Avif,
+    Gif,
 }
 
 impl SupportedImageFormat {
-    pub fn to_image_format(&self) -> ImageFormat {
+    pub fn to_image_format(self) -> ImageFormat {
         match self {
             SupportedImageFormat::Png => ImageFormat::Png,
             SupportedImageFormat::Webp => ImageFormat::WebP,
+            SupportedImageFormat::Jpeg => ImageFormat::Jpeg,
+            SupportedImageFormat::Avif => ImageFormat::Avif,
+            SupportedImageFormat::Gif => ImageFormat::Gif,
         }
     }
 
-    pub fn to_string(&self) -> &'static str {
+    pub fn as_str(self) -> &'static str {
         match self {
             SupportedImageFormat::Png => "png",
             SupportedImageFormat::Webp => "webp",
+            SupportedImageFormat::Jpeg => "jpeg",
+            SupportedImageFormat::Avif => "avif",
+            SupportedImageFormat::Gif => "gif",
+        }
+    }
+}
+
+impl std::fmt::Display for SupportedImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WebpMode {
+    /// Exact pixels, larger files
+    Lossless,
+    /// Smaller files at the chosen quality
+    Lossy,
+}
+
+impl WebpMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebpMode::Lossless => "lossless",
+            WebpMode::Lossy => "lossy",
+        }
+    }
+}
+
+impl std::fmt::Display for WebpMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    Balanced,
+    /// Smallest files, slowest to write
+    Best,
+}
+
+impl PngCompression {
+    pub fn to_compression_type(self) -> CompressionType {
+        match self {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Balanced => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PngCompression::Fast => "fast",
+            PngCompression::Balanced => "balanced",
+            PngCompression::Best => "best",
         }
     }
 }
 
+impl std::fmt::Display for PngCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Picks the best filter for every row
+    Adaptive,
+}
+
+impl PngFilter {
+    pub fn to_filter_type(self) -> FilterType {
+        match self {
+            PngFilter::None => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+            PngFilter::Adaptive => FilterType::Adaptive,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PngFilter::None => "none",
+            PngFilter::Sub => "sub",
+            PngFilter::Up => "up",
+            PngFilter::Avg => "avg",
+            PngFilter::Paeth => "paeth",
+            PngFilter::Adaptive => "adaptive",
+        }
+    }
+}
+
+impl std::fmt::Display for PngFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 pub fn read_filepath() -> Result<PathBuf, AppError> {
     let stdin = io::stdin();
     let mut lines = stdin.lock().lines();
@@ -81,44 +432,137 @@ pub fn read_filepath() -> Result<PathBuf, AppError> {
         }
     };
 
-    Ok(PathBuf::new().join(filepath.trim().to_string()))
+    Ok(PathBuf::new().join(filepath.trim()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use image::Rgb;
 
     #[test]
     fn test_cli_default_values() {
         let args = vec![env!("CARGO_PKG_NAME")];
         let cli = Cli::parse_from(args);
 
-        assert_eq!(cli.dims, 256);
-        assert_eq!(cli.service, UploadServiceIdentifier::Imgur);
-        assert_eq!(cli.output, OutputFormat::Url);
-        assert_eq!(cli.format, SupportedImageFormat::Png);
+        // unset options fall back to the config file, then to the defaults in `Config`
+        assert!(cli.config.is_none());
+        assert!(!cli.show_config);
+        assert!(cli.profile.is_none());
+        assert!(cli.dims.is_none());
+        assert!(cli.width.is_none());
+        assert!(cli.height.is_none());
+        assert!(cli.resize.is_none());
+        assert!(cli.background.is_none());
+        assert!(cli.service.is_none());
+        assert!(cli.output.is_none());
+        assert!(cli.template.is_none());
+        assert!(cli.format.is_none());
         assert!(cli.uid.is_none());
+        assert!(cli.cache.is_none());
+        assert!(!cli.no_cache);
+        assert!(cli.cache_ttl.is_none());
+        assert!(!cli.revalidate);
+        assert!(cli.retries.is_none());
+        assert!(cli.retry_delay.is_none());
+        assert!(cli.connect_timeout.is_none());
+        assert!(cli.request_timeout.is_none());
+        assert!(cli.timeout.is_none());
+        assert!(cli.folder_art.is_none());
     }
 
     #[test]
     fn test_cli_custom_values() {
         let args = vec![
             env!("CARGO_PKG_NAME"),
+            "--config",
+            "config.toml",
+            "--profile",
+            "forum",
             "--dims",
-            "512",
+            "512x256",
+            "--width",
+            "640",
+            "--height",
+            "320",
+            "--resize",
+            "pad",
+            "--background",
+            "#ffffff",
             "--service",
             "catbox",
             "--format",
             "webp",
             "--uid",
             "test_user",
+            "--cache",
+            "cache.json",
+            "--no-cache",
+            "--cache-ttl",
+            "3600",
+            "--revalidate",
+            "--folder-art",
+            "front,cover",
+            "--retries",
+            "5",
+            "--retry-delay",
+            "100",
+            "--connect-timeout",
+            "1",
+            "--request-timeout",
+            "2",
+            "--timeout",
+            "3",
+            "--output",
+            "template",
+            "--template",
+            "{url}",
         ];
         let cli = Cli::parse_from(args);
 
-        assert_eq!(cli.dims, 512);
-        assert_eq!(cli.service, UploadServiceIdentifier::Catbox);
-        assert_eq!(cli.format, SupportedImageFormat::Webp);
+        assert_eq!(cli.config, Some(PathBuf::from("config.toml")));
+        assert_eq!(cli.profile, Some("forum".to_string()));
+        assert_eq!(
+            cli.dims,
+            Some(Dimensions {
+                width: 512,
+                height: 256
+            })
+        );
+        assert_eq!((cli.width, cli.height), (Some(640), Some(320)));
+        assert_eq!(cli.resize, Some(ResizeMode::Pad));
+        assert_eq!(cli.background, Some("#ffffff".parse().unwrap()));
+        assert_eq!(cli.service, Some(vec![UploadServiceIdentifier::Catbox]));
+        assert_eq!(cli.format, Some(SupportedImageFormat::Webp));
         assert_eq!(cli.uid, Some("test_user".to_string()));
+        assert_eq!(cli.cache, Some(PathBuf::from("cache.json")));
+        assert!(cli.no_cache);
+        assert_eq!(cli.cache_ttl, Some(3600));
+        assert!(cli.revalidate);
+        assert_eq!(cli.retries, Some(5));
+        assert_eq!(cli.retry_delay, Some(100));
+        assert_eq!(
+            (cli.connect_timeout, cli.request_timeout, cli.timeout),
+            (Some(1), Some(2), Some(3))
+        );
+        assert_eq!(
+            cli.folder_art,
+            Some(vec!["front".to_string(), "cover".to_string()])
+        );
+        assert_eq!(cli.output, Some(OutputFormat::Template));
+        assert_eq!(cli.template, Some("{url}".to_string()));
+    }
+
+    #[test]
+    fn test_cli_service_chain() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "imgur,catbox"]);
+        assert_eq!(
+            cli.service,
+            Some(vec![
+                UploadServiceIdentifier::Imgur,
+                UploadServiceIdentifier::Catbox
+            ])
+        );
     }
 
     #[test]
@@ -132,7 +576,120 @@ mod tests {
             ImageFormat::WebP
         );
 
+        assert_eq!(
+            SupportedImageFormat::Jpeg.to_image_format(),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(
+            SupportedImageFormat::Avif.to_image_format(),
+            ImageFormat::Avif
+        );
+        assert_eq!(
+            SupportedImageFormat::Gif.to_image_format(),
+            ImageFormat::Gif
+        );
+
         assert_eq!(SupportedImageFormat::Png.to_string(), "png");
         assert_eq!(SupportedImageFormat::Webp.to_string(), "webp");
+        assert_eq!(SupportedImageFormat::Jpeg.to_string(), "jpeg");
+    }
+
+    #[test]
+    fn test_cli_resampling() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--filter",
+            "catmull-rom",
+            "--linear",
+        ]);
+        assert_eq!(cli.filter, Some(ResampleFilter::CatmullRom));
+        assert!(cli.linear);
+
+        for filter in ResampleFilter::value_variants() {
+            let parsed = ResampleFilter::from_str(filter.as_str(), false).unwrap();
+            assert_eq!(parsed, *filter);
+        }
+    }
+
+    #[test]
+    fn test_cli_decode_limits() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--max-file-size",
+            "1000000",
+            "--max-source-dims",
+            "4096",
+            "--max-alloc",
+            "64000000",
+        ]);
+        assert_eq!(cli.max_file_size, Some(1_000_000));
+        assert_eq!(cli.max_source_dims, Some(Dimensions::square(4096)));
+        assert_eq!(cli.max_alloc, Some(64_000_000));
+    }
+
+    #[test]
+    fn test_cli_alpha() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME")]);
+        assert_eq!(cli.alpha, None);
+
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--alpha", "#102030"]);
+        assert_eq!(cli.alpha, Some(Alpha::Flatten(Rgb([16, 32, 48]))));
+
+        assert!(Cli::try_parse_from([env!("CARGO_PKG_NAME"), "--alpha", "blur"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_animation() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--frame", "2"]);
+        assert_eq!(cli.frame, Some(2));
+        assert!(!cli.animate);
+
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--animate"]);
+        assert_eq!(cli.frame, None);
+        assert!(cli.animate);
+    }
+
+    #[test]
+    fn test_cli_upscale() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--upscale",
+            "allow-up-to-2x",
+            "--min-source-size",
+            "300x200",
+        ]);
+        assert_eq!(cli.upscale, Some(Upscale::UpTo(2.0)));
+        assert_eq!(
+            cli.min_source_size,
+            Some(Dimensions {
+                width: 300,
+                height: 200
+            })
+        );
+
+        assert!(Cli::try_parse_from([env!("CARGO_PKG_NAME"), "--upscale", "always"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_encoder_settings() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--webp",
+            "lossy",
+            "--png-compression",
+            "best",
+            "--png-filter",
+            "paeth",
+        ]);
+        assert_eq!(cli.webp, Some(WebpMode::Lossy));
+        assert_eq!(cli.png_compression, Some(PngCompression::Best));
+        assert_eq!(cli.png_filter, Some(PngFilter::Paeth));
+    }
+
+    #[test]
+    fn test_cli_jpeg_quality() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--format", "jpg", "--quality", "70"]);
+        assert_eq!(cli.format, Some(SupportedImageFormat::Jpeg));
+        assert_eq!(cli.quality, Some(70));
     }
 }
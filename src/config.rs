@@ -1,70 +1,1008 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use image::ImageFormat;
+
 use crate::{
-    cli::{Cli, SupportedImageFormat},
+    artwork::DEFAULT_FOLDER_ART,
+    cache::UploadCache,
+    cli::{Cli, OutputFormat, ResampleFilter, ResizeMode, SupportedImageFormat},
+    config_file::{ConfigFile, Profile},
     errors::AppError,
-    uploaders::UploadServiceIdentifier,
+    image_processor::{
+        can_animate, Alpha, Animation, Background, DecodeLimits, Dimensions, Encoding,
+        ImageProcessingOptions, Resampling, Upscale,
+    },
+    output::validate_template,
+    uploaders::{retry::RetryPolicy, Timeouts, UploadServiceIdentifier, UploadTarget},
 };
 
 static UASTRING: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
+/// Where a setting got its value, so errors can point at the flag or key to fix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    /// Command line flag, without the leading dashes
+    Cli(&'static str),
+    /// Environment variable read at runtime
+    Env(&'static str),
+    /// Key in the config file, e.g. `catbox.format`
+    File(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Cli(flag) => write!(f, "--{}", flag),
+            Source::Env(name) => write!(f, "${}", name),
+            Source::File(key) => write!(f, "`{}` in the config file", key),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
-    pub service: UploadServiceIdentifier,
+    /// Services to try, in order, until one accepts the upload
+    pub services: Vec<UploadServiceIdentifier>,
     pub image_format: SupportedImageFormat,
+    pub encoding: Encoding,
+    /// Size budget for the encoded thumbnail
+    pub max_bytes: Option<u64>,
     pub image_dimensions: (u32, u32),
-    pub client_id: Option<String>,
+    pub resize_mode: ResizeMode,
+    pub filter: ResampleFilter,
+    /// Resample in linear light
+    pub linear: bool,
+    pub upscale: Upscale,
+    pub min_source_size: Option<Dimensions>,
+    /// Frame of an animated source used for a still thumbnail, counting from 1
+    pub frame: u32,
+    /// Keep animated sources animated
+    pub animate: bool,
+    pub background: Background,
+    pub alpha: Alpha,
+    pub credentials: HashMap<UploadServiceIdentifier, String>,
+    /// Upload urls replacing the services' own, from the config file
+    pub endpoints: HashMap<UploadServiceIdentifier, String>,
+    /// Formats fallback services re-encode to, from the config file
+    pub service_formats: HashMap<UploadServiceIdentifier, SupportedImageFormat>,
     pub user_agent: &'static str,
+    pub cache_path: Option<PathBuf>,
+    /// Overrides the per-service cache ttl when set
+    pub cache_ttl: Option<Duration>,
+    pub cache_revalidate: bool,
+    pub folder_art: Vec<String>,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    pub limits: DecodeLimits,
+    pub output: OutputFormat,
+    pub template: Option<String>,
+    /// Profile from the config file the settings were merged with
+    pub profile: Option<String>,
+    /// Where each merged setting came from, keyed by its config file name
+    pub sources: HashMap<String, Source>,
+}
+
+/// Setting name and environment variable holding each service's credential
+fn credential_names(service: UploadServiceIdentifier) -> (&'static str, &'static str) {
+    match service {
+        UploadServiceIdentifier::Imgur => ("imgur.client_id", "RTU_IMGUR_CLIENT_ID"),
+        UploadServiceIdentifier::Catbox => ("catbox.userhash", "RTU_CATBOX_USERHASH"),
+    }
+}
+
+/// Takes the first value that is set, remembering where it came from
+fn pick<T>(
+    sources: &mut HashMap<String, Source>,
+    setting: &str,
+    candidates: Vec<(Option<T>, Source)>,
+    default: T,
+) -> T {
+    for (value, source) in candidates {
+        if let Some(value) = value {
+            sources.insert(setting.to_string(), source);
+            return value;
+        }
+    }
+
+    sources.insert(setting.to_string(), Source::Default);
+    default
+}
+
+/// `pick` over the command line value, the environment value if the setting has one, then `get`
+/// applied to each config file layer, each under its own key prefix
+fn pick_layered<T>(
+    sources: &mut HashMap<String, Source>,
+    layers: &[(String, Profile)],
+    setting: &str,
+    cli: (Option<T>, Source),
+    env: Option<(Option<T>, Source)>,
+    get: impl Fn(&Profile) -> Option<T>,
+    default: T,
+) -> T {
+    let mut candidates = vec![cli];
+    candidates.extend(env);
+    for (prefix, layer) in layers {
+        candidates.push((get(layer), Source::File(format!("{}{}", prefix, setting))));
+    }
+
+    pick(sources, setting, candidates, default)
 }
 
 impl Config {
+    /// Loads the config file (from `--config` or the default locations) and merges it with the
+    /// flags and the process environment
     pub fn new(options: &Cli) -> Result<Self, AppError> {
-        // Get credentials from environment variables at compile time
-        let imgur_client_id_env = option_env!("IMGUR_CLIENT_ID");
+        let file = ConfigFile::load(options.config.as_deref())?;
+        Config::from_sources(options, &file, |name| std::env::var(name).ok())
+    }
+
+    /// Merges command line flags over environment variables over config file values over defaults
+    pub fn from_sources(
+        options: &Cli,
+        file: &ConfigFile,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, AppError> {
+        let mut sources = HashMap::new();
+        let cli = |flag| Source::Cli(flag);
+        let key = |key: &str| Source::File(key.to_string());
+        // flags can only switch these on, so an unset flag leaves the file value
+        let flag = |set: bool| set.then_some(true);
 
-        // use provided id if set, else use environment variable
-        let user_id: Option<String> = options.uid.clone();
+        // the selected profile's values come before the file's own
+        let layers = file.layers(options.profile.as_deref())?;
+        if options.profile.is_some() {
+            sources.insert("profile".to_string(), cli("profile"));
+        }
+        let in_layers = |name: &str| {
+            layers
+                .iter()
+                .map(|(prefix, layer)| (layer, key(&format!("{}{}", prefix, name))))
+                .collect::<Vec<_>>()
+        };
 
-        let client_id = match options.service {
-            UploadServiceIdentifier::Imgur => {
-                user_id.clone().or(imgur_client_id_env.map(str::to_string))
+        let services = pick_layered(
+            &mut sources,
+            &layers,
+            "service",
+            (options.service.clone(), cli("service")),
+            None,
+            |layer| layer.service.clone(),
+            vec![UploadServiceIdentifier::Imgur],
+        );
+
+        // the first service's section refines the global values of the same layer
+        let primary = services.first().copied();
+        let section_key = |name: &str| match primary {
+            Some(service) => format!("{}.{}", service, name),
+            None => name.to_string(),
+        };
+
+        // --width and --height override one side of the box, each side is merged on its own
+        let mut dims = vec![(options.dims, cli("dims"))];
+        for ((layer, section_source), (_, source)) in in_layers(&section_key("dims"))
+            .into_iter()
+            .zip(in_layers("dims"))
+        {
+            dims.push((primary.and_then(|s| layer.section(s).dims), section_source));
+            dims.push((layer.dims, source));
+        }
+        let side = |flag: Option<u32>, name, get: fn(Dimensions) -> u32| {
+            let mut candidates = vec![(flag, cli(name))];
+            for (value, source) in &dims {
+                candidates.push((value.map(get), source.clone()));
             }
-            UploadServiceIdentifier::Catbox => user_id,
+            candidates
         };
+        let width = pick(
+            &mut sources,
+            "width",
+            side(options.width, "width", |d| d.width),
+            256,
+        );
+        let height = pick(
+            &mut sources,
+            "height",
+            side(options.height, "height", |d| d.height),
+            256,
+        );
+
+        let resize_mode = pick_layered(
+            &mut sources,
+            &layers,
+            "resize",
+            (options.resize, cli("resize")),
+            None,
+            |layer| layer.resize,
+            ResizeMode::Fit,
+        );
+
+        let filter = pick_layered(
+            &mut sources,
+            &layers,
+            "filter",
+            (options.filter, cli("filter")),
+            None,
+            |layer| layer.filter,
+            ResampleFilter::CatmullRom,
+        );
+
+        let linear = pick_layered(
+            &mut sources,
+            &layers,
+            "linear",
+            (flag(options.linear), cli("linear")),
+            None,
+            |layer| layer.linear,
+            false,
+        );
+
+        let upscale = pick_layered(
+            &mut sources,
+            &layers,
+            "upscale",
+            (options.upscale, cli("upscale")),
+            None,
+            |layer| layer.upscale,
+            Upscale::Allow,
+        );
+
+        let min_source_size = pick_layered(
+            &mut sources,
+            &layers,
+            "min_source_size",
+            (options.min_source_size.map(Some), cli("min-source-size")),
+            None,
+            |layer| layer.min_source_size.map(Some),
+            None,
+        );
+
+        let frame = pick_layered(
+            &mut sources,
+            &layers,
+            "frame",
+            (options.frame, cli("frame")),
+            None,
+            |layer| layer.frame,
+            1,
+        );
+
+        let animate = pick_layered(
+            &mut sources,
+            &layers,
+            "animate",
+            (flag(options.animate), cli("animate")),
+            None,
+            |layer| layer.animate,
+            false,
+        );
+
+        let background = pick_layered(
+            &mut sources,
+            &layers,
+            "background",
+            (options.background, cli("background")),
+            None,
+            |layer| layer.background,
+            Background::default(),
+        );
+
+        let alpha = pick_layered(
+            &mut sources,
+            &layers,
+            "alpha",
+            (options.alpha, cli("alpha")),
+            None,
+            |layer| layer.alpha,
+            Alpha::Keep,
+        );
+
+        let mut candidates = vec![(options.format, cli("format"))];
+        for ((layer, section_source), (_, source)) in in_layers(&section_key("format"))
+            .into_iter()
+            .zip(in_layers("format"))
+        {
+            candidates.push((
+                primary.and_then(|s| layer.section(s).format),
+                section_source,
+            ));
+            candidates.push((layer.format, source));
+        }
+        let image_format = pick(
+            &mut sources,
+            "format",
+            candidates,
+            SupportedImageFormat::Png,
+        );
+
+        let defaults = Encoding::default();
+        let quality = pick_layered(
+            &mut sources,
+            &layers,
+            "quality",
+            (options.quality, cli("quality")),
+            None,
+            |layer| layer.quality,
+            defaults.quality,
+        );
+
+        let webp = pick_layered(
+            &mut sources,
+            &layers,
+            "webp",
+            (options.webp, cli("webp")),
+            None,
+            |layer| layer.webp,
+            defaults.webp,
+        );
+
+        let png_compression = pick_layered(
+            &mut sources,
+            &layers,
+            "png_compression",
+            (options.png_compression, cli("png-compression")),
+            None,
+            |layer| layer.png_compression,
+            defaults.png_compression,
+        );
+
+        let png_filter = pick_layered(
+            &mut sources,
+            &layers,
+            "png_filter",
+            (options.png_filter, cli("png-filter")),
+            None,
+            |layer| layer.png_filter,
+            defaults.png_filter,
+        );
+
+        let max_bytes = pick_layered(
+            &mut sources,
+            &layers,
+            "max_bytes",
+            (options.max_bytes.map(Some), cli("max-bytes")),
+            None,
+            |layer| layer.max_bytes.map(Some),
+            None,
+        );
+
+        // client id baked into release builds, anything given at runtime replaces it
+        let compiled_client_id = option_env!("IMGUR_CLIENT_ID");
+
+        let mut credentials = HashMap::new();
+        let mut endpoints = HashMap::new();
+        let mut service_formats = HashMap::new();
+        for service in [
+            UploadServiceIdentifier::Imgur,
+            UploadServiceIdentifier::Catbox,
+        ] {
+            let (setting, variable) = credential_names(service);
+
+            // provided id only applies to the first service
+            let uid = options.uid.clone().filter(|_| primary == Some(service));
+            let compiled = match service {
+                UploadServiceIdentifier::Imgur => compiled_client_id.map(String::from),
+                UploadServiceIdentifier::Catbox => None,
+            };
+
+            let credential = pick_layered(
+                &mut sources,
+                &layers,
+                setting,
+                (uid.map(Some), cli("uid")),
+                Some((
+                    env(variable).filter(|v| !v.is_empty()).map(Some),
+                    Source::Env(variable),
+                )),
+                |layer| layer.section(service).client_id.clone().map(Some),
+                compiled,
+            );
+            if let Some(credential) = credential {
+                credentials.insert(service, credential);
+            }
+
+            let setting = format!("{}.endpoint", service);
+            let candidates = in_layers(&setting)
+                .into_iter()
+                .map(|(layer, source)| (layer.section(service).endpoint.clone().map(Some), source))
+                .collect();
+            if let Some(endpoint) = pick(&mut sources, &setting, candidates, None) {
+                endpoints.insert(service, endpoint);
+            }
+
+            let setting = format!("{}.format", service);
+            let candidates = in_layers(&setting)
+                .into_iter()
+                .map(|(layer, source)| (layer.section(service).format.map(Some), source))
+                .collect();
+            if let Some(format) = pick(&mut sources, &setting, candidates, None) {
+                service_formats.insert(service, format);
+            }
+        }
+
+        // no cache path means caching is disabled
+        let no_cache = pick(
+            &mut sources,
+            "no_cache",
+            vec![
+                (flag(options.no_cache), cli("no-cache")),
+                (file.no_cache, key("no_cache")),
+            ],
+            false,
+        );
+        let cache_path = if no_cache {
+            None
+        } else {
+            pick(
+                &mut sources,
+                "cache",
+                vec![
+                    (options.cache.clone().map(Some), cli("cache")),
+                    (file.cache.clone().map(Some), key("cache")),
+                ],
+                None,
+            )
+            .or_else(UploadCache::default_path)
+        };
+
+        let cache_ttl = pick(
+            &mut sources,
+            "cache_ttl",
+            vec![
+                (options.cache_ttl.map(Some), cli("cache-ttl")),
+                (file.cache_ttl.map(Some), key("cache_ttl")),
+            ],
+            None,
+        );
+
+        let cache_revalidate = pick(
+            &mut sources,
+            "revalidate",
+            vec![
+                (flag(options.revalidate), cli("revalidate")),
+                (file.revalidate, key("revalidate")),
+            ],
+            false,
+        );
+
+        let folder_art = pick(
+            &mut sources,
+            "folder_art",
+            vec![
+                (options.folder_art.clone(), cli("folder-art")),
+                (file.folder_art.clone(), key("folder_art")),
+            ],
+            DEFAULT_FOLDER_ART.map(String::from).to_vec(),
+        );
+
+        let defaults = RetryPolicy::default();
+        let retries = pick(
+            &mut sources,
+            "retries",
+            vec![
+                (options.retries, cli("retries")),
+                (file.retries, key("retries")),
+            ],
+            defaults.retries,
+        );
+        let retry_delay = pick(
+            &mut sources,
+            "retry_delay",
+            vec![
+                (options.retry_delay, cli("retry-delay")),
+                (file.retry_delay, key("retry_delay")),
+            ],
+            defaults.base_delay.as_millis() as u64,
+        );
+
+        let defaults = Timeouts::default();
+        let connect_timeout = pick(
+            &mut sources,
+            "connect_timeout",
+            vec![
+                (options.connect_timeout, cli("connect-timeout")),
+                (file.connect_timeout, key("connect_timeout")),
+            ],
+            defaults.connect.as_secs(),
+        );
+        let request_timeout = pick(
+            &mut sources,
+            "request_timeout",
+            vec![
+                (options.request_timeout, cli("request-timeout")),
+                (file.request_timeout, key("request_timeout")),
+            ],
+            defaults.request.as_secs(),
+        );
+        let timeout = pick(
+            &mut sources,
+            "timeout",
+            vec![
+                (options.timeout, cli("timeout")),
+                (file.timeout, key("timeout")),
+            ],
+            defaults.total.as_secs(),
+        );
+
+        let defaults = DecodeLimits::default();
+        let max_file_size = pick(
+            &mut sources,
+            "max_file_size",
+            vec![
+                (options.max_file_size, cli("max-file-size")),
+                (file.max_file_size, key("max_file_size")),
+            ],
+            defaults.max_file_size,
+        );
+        let max_source_dims = pick(
+            &mut sources,
+            "max_source_dims",
+            vec![
+                (options.max_source_dims, cli("max-source-dims")),
+                (file.max_source_dims, key("max_source_dims")),
+            ],
+            defaults.max_dimensions,
+        );
+        let max_alloc = pick(
+            &mut sources,
+            "max_alloc",
+            vec![
+                (options.max_alloc, cli("max-alloc")),
+                (file.max_alloc, key("max_alloc")),
+            ],
+            defaults.max_alloc,
+        );
+
+        let output = pick_layered(
+            &mut sources,
+            &layers,
+            "output",
+            (options.output, cli("output")),
+            None,
+            |layer| layer.output,
+            OutputFormat::Url,
+        );
+
+        let template = pick_layered(
+            &mut sources,
+            &layers,
+            "template",
+            (options.template.clone().map(Some), cli("template")),
+            None,
+            |layer| layer.template.clone().map(Some),
+            None,
+        );
 
         let config = Config {
-            service: options.service,
-            client_id: client_id,
+            services,
+            credentials,
+            endpoints,
+            service_formats,
             user_agent: UASTRING,
-            image_format: options.format,
-            image_dimensions: (options.dims, options.dims),
+            image_format,
+            encoding: Encoding {
+                quality,
+                webp,
+                png_compression,
+                png_filter,
+            },
+            max_bytes,
+            image_dimensions: (width, height),
+            resize_mode,
+            filter,
+            linear,
+            upscale,
+            min_source_size,
+            frame,
+            animate,
+            background,
+            alpha,
+            cache_path,
+            cache_ttl: cache_ttl.map(Duration::from_secs),
+            cache_revalidate,
+            folder_art,
+            retry: RetryPolicy {
+                retries,
+                base_delay: Duration::from_millis(retry_delay),
+                ..RetryPolicy::default()
+            },
+            timeouts: Timeouts {
+                connect: Duration::from_secs(connect_timeout),
+                request: Duration::from_secs(request_timeout),
+                total: Duration::from_secs(timeout),
+            },
+            limits: DecodeLimits {
+                max_file_size,
+                max_dimensions: max_source_dims,
+                max_alloc,
+            },
+            output,
+            template,
+            profile: options.profile.clone(),
+            sources,
         };
 
         Config::valid(config)
     }
 
+    /// Client id for imgur, user hash for catbox
+    pub fn client_id(&self, service: UploadServiceIdentifier) -> Option<&str> {
+        self.credentials.get(&service).map(String::as_str)
+    }
+
+    /// How long a cached link from `service` is trusted
+    pub fn ttl_for(&self, service: UploadServiceIdentifier) -> Duration {
+        self.cache_ttl.unwrap_or_else(|| service.cache_ttl())
+    }
+
+    /// How the source image is turned into the thumbnail
+    pub fn processing_options(&self) -> ImageProcessingOptions {
+        ImageProcessingOptions {
+            size: Dimensions {
+                width: self.image_dimensions.0,
+                height: self.image_dimensions.1,
+            },
+            format: self.image_format.to_image_format(),
+            encoding: self.encoding,
+            max_bytes: self.max_bytes,
+            mode: self.resize_mode,
+            resampling: Resampling {
+                filter: self.filter.to_filter_type(),
+                linear: self.linear,
+            },
+            upscale: self.upscale,
+            min_source_size: self.min_source_size,
+            background: self.background,
+            alpha: self.alpha,
+            animation: if self.animate {
+                Animation::Animate
+            } else {
+                Animation::Frame(self.frame)
+            },
+            limits: self.limits,
+        }
+    }
+
+    /// Where `setting` got its value
+    pub fn source(&self, setting: &str) -> Source {
+        self.sources
+            .get(setting)
+            .cloned()
+            .unwrap_or(Source::Default)
+    }
+
+    /// Every merged setting with its value and the source that won, one per line.
+    /// Credentials are masked down to their last four characters.
+    pub fn describe(&self) -> String {
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        let mask = |secret: &str| {
+            let visible = secret.len().saturating_sub(4);
+            match secret.get(visible..) {
+                Some(tail) => format!("{}{}", "*".repeat(visible), tail),
+                None => "*".repeat(secret.chars().count()),
+            }
+        };
+
+        let mut settings = vec![
+            ("profile", or_none(self.profile.clone())),
+            (
+                "service",
+                self.services
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("width", self.image_dimensions.0.to_string()),
+            ("height", self.image_dimensions.1.to_string()),
+            ("resize", self.resize_mode.to_string()),
+            ("filter", self.filter.to_string()),
+            ("linear", self.linear.to_string()),
+            ("upscale", self.upscale.to_string()),
+            (
+                "min_source_size",
+                or_none(self.min_source_size.map(|size| size.to_string())),
+            ),
+            ("frame", self.frame.to_string()),
+            ("animate", self.animate.to_string()),
+            ("background", self.background.to_string()),
+            ("alpha", self.alpha.to_string()),
+            ("format", self.image_format.to_string()),
+            ("quality", self.encoding.quality.to_string()),
+            ("webp", self.encoding.webp.to_string()),
+            ("png_compression", self.encoding.png_compression.to_string()),
+            ("png_filter", self.encoding.png_filter.to_string()),
+            (
+                "max_bytes",
+                or_none(self.max_bytes.map(|max| max.to_string())),
+            ),
+            ("output", self.output.to_string()),
+            ("template", or_none(self.template.clone())),
+            ("folder_art", self.folder_art.join(",")),
+            ("no_cache", self.cache_path.is_none().to_string()),
+            (
+                "cache",
+                or_none(
+                    self.cache_path
+                        .as_ref()
+                        .map(|path| path.to_string_lossy().to_string()),
+                ),
+            ),
+            (
+                "cache_ttl",
+                or_none(self.cache_ttl.map(|ttl| ttl.as_secs().to_string())),
+            ),
+            ("revalidate", self.cache_revalidate.to_string()),
+            ("retries", self.retry.retries.to_string()),
+            ("retry_delay", self.retry.base_delay.as_millis().to_string()),
+            (
+                "connect_timeout",
+                self.timeouts.connect.as_secs().to_string(),
+            ),
+            (
+                "request_timeout",
+                self.timeouts.request.as_secs().to_string(),
+            ),
+            ("timeout", self.timeouts.total.as_secs().to_string()),
+            ("max_file_size", self.limits.max_file_size.to_string()),
+            ("max_source_dims", self.limits.max_dimensions.to_string()),
+            ("max_alloc", self.limits.max_alloc.to_string()),
+        ];
+
+        for service in [
+            UploadServiceIdentifier::Imgur,
+            UploadServiceIdentifier::Catbox,
+        ] {
+            let (setting, _) = credential_names(service);
+            settings.push((setting, or_none(self.client_id(service).map(mask))));
+        }
+
+        settings
+            .into_iter()
+            .map(|(setting, value)| format!("{} = {} ({})", setting, value, self.source(setting)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The fallback chain with each service's credentials and overrides
+    pub fn targets(&self) -> Vec<UploadTarget> {
+        self.services
+            .iter()
+            .enumerate()
+            .map(|(i, service)| UploadTarget {
+                client_id: self.client_id(*service).unwrap_or_default().to_string(),
+                endpoint: self.endpoints.get(service).cloned(),
+                // the thumbnail is already made in the first service's format
+                format: match i {
+                    0 => None,
+                    _ => self
+                        .service_formats
+                        .get(service)
+                        .map(|format| format.to_image_format()),
+                },
+                ..UploadTarget::new(*service)
+            })
+            .collect()
+    }
+
     fn valid(config: Config) -> Result<Self, AppError> {
-        let client_id_check = match config.service {
-            UploadServiceIdentifier::Imgur => config.client_id.is_some(),
-            UploadServiceIdentifier::Catbox => true,
+        let invalid = |setting: &str, reason: String| {
+            AppError::Config(format!("invalid {}: {}", config.source(setting), reason))
         };
 
-        if !client_id_check {
-            return Err(AppError::Config(format!(
-                "{} requires a client id",
-                config.service
-            )));
+        let Some(primary) = config.services.first() else {
+            return Err(invalid(
+                "service",
+                "at least one service is required".to_string(),
+            ));
+        };
+
+        for service in &config.services {
+            let client_id_check = match service {
+                UploadServiceIdentifier::Imgur => config.client_id(*service).is_some(),
+                UploadServiceIdentifier::Catbox => true,
+            };
+
+            if !client_id_check {
+                let (setting, variable) = credential_names(*service);
+                return Err(AppError::Config(format!(
+                    "{} requires a client id, pass --uid, set ${} or `{}` in the config file",
+                    service, variable, setting
+                )));
+            }
+        }
+
+        if config.image_dimensions.0 == 0 {
+            return Err(invalid("width", "must be greater than 0".to_string()));
+        }
+        if config.image_dimensions.1 == 0 {
+            return Err(invalid("height", "must be greater than 0".to_string()));
         }
 
-        let valid_format = config
-            .service
+        // fallback services re-encode to a format they accept, the first one must accept the choice
+        let valid_format = primary
             .formats()
             .contains(&config.image_format.to_image_format());
 
         if !valid_format {
-            return Err(AppError::Config(format!(
-                "{} is not a valid format for {}",
-                config.image_format.to_string(),
-                config.service
-            )));
+            return Err(invalid(
+                "format",
+                format!(
+                    "{} is not a valid format for {}",
+                    config.image_format, primary
+                ),
+            ));
+        }
+
+        if !(1..=100).contains(&config.encoding.quality) {
+            return Err(invalid(
+                "quality",
+                format!("{} is not between 1 and 100", config.encoding.quality),
+            ));
+        }
+
+        if config.max_bytes == Some(0) {
+            return Err(invalid("max_bytes", "must be greater than 0".to_string()));
+        }
+
+        if config.frame == 0 {
+            return Err(invalid("frame", "frames are counted from 1".to_string()));
+        }
+
+        if config.animate {
+            if config.source("frame") != Source::Default {
+                return Err(invalid(
+                    "frame",
+                    "a still frame cannot be combined with animate".to_string(),
+                ));
+            }
+            // the first service's format decides, fallbacks keep the animation when they can
+            if !can_animate(config.image_format.to_image_format()) {
+                return Err(invalid(
+                    "animate",
+                    format!(
+                        "has no effect on {}, only gif and webp thumbnails are animated",
+                        config.image_format
+                    ),
+                ));
+            }
+        }
+
+        // an encoder setting that none of the chain's formats uses is most likely a mistake
+        let formats: Vec<SupportedImageFormat> = std::iter::once(config.image_format)
+            .chain(
+                config
+                    .services
+                    .iter()
+                    .filter_map(|service| config.service_formats.get(service).copied()),
+            )
+            .collect();
+        let lossy =
+            |format: &SupportedImageFormat| config.encoding.is_lossy(format.to_image_format());
+        let settings = [
+            (
+                "quality",
+                formats.iter().any(lossy),
+                "jpeg, avif and lossy webp",
+            ),
+            (
+                "webp",
+                formats.contains(&SupportedImageFormat::Webp),
+                "webp",
+            ),
+            (
+                "png_compression",
+                formats.contains(&SupportedImageFormat::Png),
+                "png",
+            ),
+            (
+                "png_filter",
+                formats.contains(&SupportedImageFormat::Png),
+                "png",
+            ),
+        ];
+        for (setting, used, applies_to) in settings {
+            if !used && config.source(setting) != Source::Default {
+                let formats: Vec<&str> = formats.iter().map(|format| format.as_str()).collect();
+                return Err(invalid(
+                    setting,
+                    format!(
+                        "has no effect on {}, it only applies to {}",
+                        formats.join(", "),
+                        applies_to
+                    ),
+                ));
+            }
+        }
+
+        // there is no AVIF decoder to re-encode from, every fallback must take the thumbnail as is
+        if config.image_format == SupportedImageFormat::Avif {
+            let refuses = config.services.iter().skip(1).find(|service| {
+                !service.formats().contains(&ImageFormat::Avif)
+                    || config
+                        .service_formats
+                        .get(service)
+                        .is_some_and(|format| *format != SupportedImageFormat::Avif)
+            });
+            if let Some(service) = refuses {
+                return Err(invalid(
+                    "format",
+                    format!("avif thumbnails cannot be re-encoded for {}", service),
+                ));
+            }
+        }
+
+        for service in [
+            UploadServiceIdentifier::Imgur,
+            UploadServiceIdentifier::Catbox,
+        ] {
+            if let Some(format) = config.service_formats.get(&service) {
+                if !service.formats().contains(&format.to_image_format()) {
+                    return Err(invalid(
+                        &format!("{}.format", service),
+                        format!("{} is not a valid format for {}", format, service),
+                    ));
+                }
+            }
+
+            if let Some(endpoint) = config.endpoints.get(&service) {
+                if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") {
+                    return Err(invalid(
+                        &format!("{}.endpoint", service),
+                        format!("{} is not an http(s) url", endpoint),
+                    ));
+                }
+            }
+        }
+
+        let timeouts = [
+            ("connect_timeout", config.timeouts.connect),
+            ("request_timeout", config.timeouts.request),
+            ("timeout", config.timeouts.total),
+        ];
+        for (setting, timeout) in timeouts {
+            if timeout.is_zero() {
+                return Err(invalid(
+                    setting,
+                    "timeouts must be at least one second".to_string(),
+                ));
+            }
+        }
+
+        let limits = [
+            ("max_file_size", config.limits.max_file_size),
+            ("max_source_dims", config.limits.max_dimensions.width as u64),
+            (
+                "max_source_dims",
+                config.limits.max_dimensions.height as u64,
+            ),
+            ("max_alloc", config.limits.max_alloc),
+        ];
+        for (setting, limit) in limits {
+            if limit == 0 {
+                return Err(invalid(setting, "must be greater than 0".to_string()));
+            }
+        }
+
+        // catch template mistakes before anything is uploaded
+        match (&config.output, &config.template) {
+            (OutputFormat::Template, Some(template)) => {
+                validate_template(template).map_err(|e| match e {
+                    AppError::Config(reason) => invalid("template", reason),
+                    e => e,
+                })?
+            }
+            (OutputFormat::Template, None) => {
+                return Err(AppError::Config(
+                    "template output requires --template or `template` in the config file"
+                        .to_string(),
+                ))
+            }
+            _ => {}
         }
 
         Ok(config)
@@ -74,67 +1012,664 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cli::SupportedImageFormat;
+    use crate::cli::{SupportedImageFormat, WebpMode};
+    use clap::Parser;
+
+    fn test_cli(
+        service: UploadServiceIdentifier,
+        uid: Option<String>,
+        format: SupportedImageFormat,
+    ) -> Cli {
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--dims", "128"]);
+        cli.service = Some(vec![service]);
+        cli.uid = uid;
+        cli.format = Some(format);
+        cli
+    }
 
     #[test]
     fn test_config_new_with_imgur_and_client_id() {
-        let cli = Cli {
-            service: UploadServiceIdentifier::Imgur,
-            uid: Some("test_client_id".to_string()),
-            format: SupportedImageFormat::Png,
-            dims: 128,
-            output: crate::cli::OutputFormat::Url,
-        };
+        let cli = test_cli(
+            UploadServiceIdentifier::Imgur,
+            Some("test_client_id".to_string()),
+            SupportedImageFormat::Png,
+        );
 
-        let config = Config::new(&cli).unwrap();
-        assert_eq!(config.service, UploadServiceIdentifier::Imgur);
-        assert_eq!(config.client_id, Some("test_client_id".to_string()));
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Imgur]);
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("test_client_id")
+        );
         assert_eq!(config.image_format, SupportedImageFormat::Png);
         assert_eq!(config.image_dimensions, (128, 128));
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.timeouts, Timeouts::default());
     }
 
     #[test]
     fn test_config_new_with_imgur_no_client_id() {
-        let cli = Cli {
-            service: UploadServiceIdentifier::Imgur,
-            uid: None,
-            format: SupportedImageFormat::Png,
-            dims: 128,
-            output: crate::cli::OutputFormat::Url,
-        };
+        let cli = test_cli(
+            UploadServiceIdentifier::Imgur,
+            None,
+            SupportedImageFormat::Png,
+        );
 
-        let result = Config::new(&cli);
+        let result = Config::from_sources(&cli, &ConfigFile::default(), no_env);
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), AppError::Config(_)));
     }
 
     #[test]
     fn test_config_new_with_catbox() {
-        let cli = Cli {
-            service: UploadServiceIdentifier::Catbox,
-            uid: None,
-            format: SupportedImageFormat::Png,
-            dims: 128,
-            output: crate::cli::OutputFormat::Url,
+        let cli = test_cli(
+            UploadServiceIdentifier::Catbox,
+            None,
+            SupportedImageFormat::Png,
+        );
+
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Catbox]);
+        assert_eq!(config.client_id(UploadServiceIdentifier::Catbox), None);
+    }
+
+    #[test]
+    fn test_config_fallback_chain() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "imgur,catbox",
+            "--uid",
+            "test_client_id",
+        ]);
+
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(
+            config.services,
+            vec![
+                UploadServiceIdentifier::Imgur,
+                UploadServiceIdentifier::Catbox
+            ]
+        );
+        // the uid belongs to the first service only
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("test_client_id")
+        );
+        assert_eq!(config.client_id(UploadServiceIdentifier::Catbox), None);
+
+        // imgur as a fallback still needs a client id
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox,imgur"]);
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(_)
+        ));
+
+        // the format is checked against the first service only, fallbacks re-encode
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "imgur,catbox",
+            "--format",
+            "webp",
+            "--uid",
+            "test_client_id",
+        ]);
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(e) if e == "invalid --format: webp is not a valid format for imgur"
+        ));
+    }
+
+    #[test]
+    fn test_config_quality() {
+        let mut cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox",
+            "--format",
+            "jpeg",
+        ]);
+        cli.quality = Some(0);
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(e) if e == "invalid --quality: 0 is not between 1 and 100"
+        ));
+
+        cli.quality = Some(60);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.processing_options().encoding.quality, 60);
+        assert_eq!(config.processing_options().format, ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_config_max_bytes() {
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox"]);
+        cli.max_bytes = Some(0);
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(e) if e == "invalid --max-bytes: must be greater than 0"
+        ));
+
+        cli.max_bytes = Some(200_000);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.processing_options().max_bytes, Some(200_000));
+    }
+
+    #[test]
+    fn test_config_animation() {
+        let error = |args: &[&str]| {
+            let cli =
+                Cli::parse_from([&[env!("CARGO_PKG_NAME"), "--service", "catbox"], args].concat());
+            match Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err() {
+                AppError::Config(message) => message,
+                e => panic!("unexpected error {}", e),
+            }
         };
 
-        let config = Config::new(&cli).unwrap();
-        assert_eq!(config.service, UploadServiceIdentifier::Catbox);
-        assert_eq!(config.client_id, None);
+        assert_eq!(
+            error(&["--frame", "0"]),
+            "invalid --frame: frames are counted from 1"
+        );
+        assert_eq!(
+            error(&["--animate"]),
+            "invalid --animate: has no effect on png, only gif and webp thumbnails are animated"
+        );
+        assert_eq!(
+            error(&["--animate", "--format", "gif", "--frame", "2"]),
+            "invalid --frame: a still frame cannot be combined with animate"
+        );
+
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox",
+            "--frame",
+            "3",
+        ]);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.processing_options().animation, Animation::Frame(3));
+
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox",
+            "--format",
+            "webp",
+            "--animate",
+        ]);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.processing_options().animation, Animation::Animate);
     }
 
     #[test]
-    fn test_config_with_invalid_format() {
-        let cli = Cli {
-            service: UploadServiceIdentifier::Imgur,
-            uid: Some("test_client_id".to_string()),
-            format: SupportedImageFormat::Webp, // Assuming Webp is not supported by Imgur
-            dims: 128,
-            output: crate::cli::OutputFormat::Url,
+    fn test_config_encoder_settings_match_the_format() {
+        let cli = |args: &[&str]| {
+            Cli::parse_from(
+                [env!("CARGO_PKG_NAME"), "--service", "catbox"]
+                    .iter()
+                    .chain(args),
+            )
         };
+        let error =
+            |args: &[&str]| match Config::from_sources(&cli(args), &ConfigFile::default(), no_env)
+                .unwrap_err()
+            {
+                AppError::Config(message) => message,
+                e => panic!("unexpected error {}", e),
+            };
+
+        assert_eq!(
+            error(&["--quality", "70"]),
+            "invalid --quality: has no effect on png, it only applies to jpeg, avif and lossy webp"
+        );
+        assert_eq!(
+            error(&["-f", "webp", "--quality", "70"]),
+            "invalid --quality: has no effect on webp, it only applies to jpeg, avif and lossy webp"
+        );
+        assert_eq!(
+            error(&["-f", "jpeg", "--webp", "lossy"]),
+            "invalid --webp: has no effect on jpeg, it only applies to webp"
+        );
+        assert_eq!(
+            error(&["-f", "webp", "--png-filter", "sub"]),
+            "invalid --png-filter: has no effect on webp, it only applies to png"
+        );
+
+        let config = Config::from_sources(
+            &cli(&["-f", "webp", "--webp", "lossy", "--quality", "70"]),
+            &ConfigFile::default(),
+            no_env,
+        )
+        .unwrap();
+        assert_eq!(config.encoding.webp, WebpMode::Lossy);
+        assert_eq!(config.encoding.quality, 70);
+
+        // a fallback's format counts too
+        let file = file("[imgur]\nformat = \"jpeg\"\nclient_id = \"test_client_id\"");
+        let cli = cli(&["--service", "catbox,imgur", "--quality", "70"]);
+        assert!(Config::from_sources(&cli, &file, no_env).is_ok());
+    }
+
+    #[test]
+    fn test_config_avif_is_not_re_encoded() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox,imgur",
+            "--format",
+            "avif",
+            "--uid",
+            "test_userhash",
+        ]);
+        let file = file("[imgur]\nclient_id = \"test_client_id\"");
+
+        assert!(matches!(
+            Config::from_sources(&cli, &file, no_env).unwrap_err(),
+            AppError::Config(e) if e == "invalid --format: avif thumbnails cannot be re-encoded for imgur"
+        ));
+
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox", "-f", "avif"]);
+        assert!(Config::from_sources(&cli, &file, no_env).is_ok());
+    }
+
+    #[test]
+    fn test_config_template_validation() {
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox"]);
+        cli.output = Some(OutputFormat::Template);
+
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(_)
+        ));
+
+        cli.template = Some("{url} {nope}".to_string());
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(_)
+        ));
+
+        cli.template = Some("[img]{url}[/img]".to_string());
+        assert!(Config::from_sources(&cli, &ConfigFile::default(), no_env).is_ok());
+    }
+
+    #[test]
+    fn test_config_zero_timeout() {
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox"]);
+        cli.request_timeout = Some(0);
+
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(e) if e.starts_with("invalid --request-timeout:")
+        ));
+    }
+
+    #[test]
+    fn test_config_decode_limits() {
+        let mut cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "catbox",
+            "--max-source-dims",
+            "8000x4000",
+        ]);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(
+            config.processing_options().limits,
+            DecodeLimits {
+                max_dimensions: Dimensions {
+                    width: 8000,
+                    height: 4000
+                },
+                ..DecodeLimits::default()
+            }
+        );
+
+        cli.max_alloc = Some(0);
+        assert!(matches!(
+            Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap_err(),
+            AppError::Config(e) if e == "invalid --max-alloc: must be greater than 0"
+        ));
+    }
 
-        let result = Config::new(&cli);
+    #[test]
+    fn test_config_cache_path() {
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "catbox"]);
+        cli.cache = Some(PathBuf::from("cache.json"));
+
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.cache_path, Some(PathBuf::from("cache.json")));
+
+        assert_eq!(
+            config.ttl_for(UploadServiceIdentifier::Catbox),
+            UploadServiceIdentifier::Catbox.cache_ttl()
+        );
+
+        cli.cache_ttl = Some(60);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert_eq!(
+            config.ttl_for(UploadServiceIdentifier::Imgur),
+            Duration::from_secs(60)
+        );
+
+        cli.no_cache = true;
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+        assert!(config.cache_path.is_none());
+    }
+
+    #[test]
+    fn test_config_with_invalid_format() {
+        let cli = test_cli(
+            UploadServiceIdentifier::Imgur,
+            Some("test_client_id".to_string()),
+            SupportedImageFormat::Webp,
+        ); // Assuming Webp is not supported by Imgur
+
+        let result = Config::from_sources(&cli, &ConfigFile::default(), no_env);
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), AppError::Config(_)));
     }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn file(text: &str) -> ConfigFile {
+        ConfigFile::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_config_defaults() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--uid", "test_client_id"]);
+        let config = Config::from_sources(&cli, &ConfigFile::default(), no_env).unwrap();
+
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Imgur]);
+        assert_eq!(config.image_dimensions, (256, 256));
+        assert_eq!(config.resize_mode, ResizeMode::Fit);
+        assert_eq!(
+            config.processing_options().resampling,
+            Resampling::default()
+        );
+        assert_eq!(config.upscale, Upscale::Allow);
+        assert_eq!(config.min_source_size, None);
+        assert_eq!(config.background, Background::default());
+        assert_eq!(config.alpha, Alpha::Keep);
+        assert_eq!(config.image_format, SupportedImageFormat::Png);
+        assert_eq!(config.encoding, Encoding::default());
+        assert_eq!(config.output, OutputFormat::Url);
+        assert_eq!(config.folder_art, DEFAULT_FOLDER_ART);
+        assert_eq!(config.retry, RetryPolicy::default());
+        assert_eq!(config.timeouts, Timeouts::default());
+        assert_eq!(config.limits, DecodeLimits::default());
+        assert!(config.cache_path.is_some() || UploadCache::default_path().is_none());
+        assert!(!config.cache_revalidate);
+        assert_eq!(config.source("width"), Source::Default);
+    }
+
+    #[test]
+    fn test_config_file_under_flags() {
+        let file = file(
+            r#"
+            service = ["catbox", "imgur"]
+            dims = 512
+            retries = 5
+            timeout = 20
+            no_cache = true
+            upscale = "never"
+            min_source_size = "300x200"
+
+            [imgur]
+            client_id = "file_client_id"
+            endpoint = "http://127.0.0.1:8080/3/image"
+
+            [catbox]
+            userhash = "file_hash"
+            format = "webp"
+            dims = 300
+            "#,
+        );
+
+        // the file alone, the primary service's section refines the global values
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME")]);
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(
+            config.services,
+            vec![
+                UploadServiceIdentifier::Catbox,
+                UploadServiceIdentifier::Imgur
+            ]
+        );
+        assert_eq!(config.image_dimensions, (300, 300));
+        assert_eq!(config.image_format, SupportedImageFormat::Webp);
+        assert_eq!(config.retry.retries, 5);
+        assert_eq!(config.timeouts.total, Duration::from_secs(20));
+        assert!(config.cache_path.is_none());
+        assert_eq!(config.upscale, Upscale::Never);
+        assert_eq!(
+            config.processing_options().min_source_size,
+            Some(Dimensions {
+                width: 300,
+                height: 200
+            })
+        );
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Catbox),
+            Some("file_hash")
+        );
+        assert_eq!(
+            config.source("height"),
+            Source::File("catbox.dims".to_string())
+        );
+
+        let targets = config.targets();
+        assert_eq!(targets[0].client_id, "file_hash");
+        assert_eq!(targets[0].endpoint, None);
+        assert_eq!(targets[1].client_id, "file_client_id");
+        assert_eq!(
+            targets[1].endpoint.as_deref(),
+            Some("http://127.0.0.1:8080/3/image")
+        );
+
+        // flags win over the file
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--service",
+            "imgur",
+            "--dims",
+            "128",
+            "--retries",
+            "0",
+            "--uid",
+            "cli_client_id",
+            "--upscale",
+            "allow-up-to-2x",
+        ]);
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Imgur]);
+        assert_eq!(config.image_dimensions, (128, 128));
+        assert_eq!(config.image_format, SupportedImageFormat::Png);
+        assert_eq!(config.retry.retries, 0);
+        assert_eq!(config.upscale, Upscale::UpTo(2.0));
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("cli_client_id")
+        );
+        assert_eq!(config.source("width"), Source::Cli("dims"));
+        assert_eq!(
+            config.source("timeout"),
+            Source::File("timeout".to_string())
+        );
+    }
+
+    #[test]
+    fn test_config_file_errors_name_the_key() {
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME")]);
+        let error = |text: &str| match Config::from_sources(&cli, &file(text), no_env).unwrap_err()
+        {
+            AppError::Config(message) => message,
+            e => panic!("unexpected error {}", e),
+        };
+
+        assert_eq!(
+            error("service = []"),
+            "invalid `service` in the config file: at least one service is required"
+        );
+        assert_eq!(
+            error("service = \"catbox\"\ndims = 0"),
+            "invalid `dims` in the config file: must be greater than 0"
+        );
+        assert_eq!(
+            error("service = \"catbox\"\n[imgur]\nformat = \"webp\""),
+            "invalid `imgur.format` in the config file: webp is not a valid format for imgur"
+        );
+        assert_eq!(
+            error("service = \"catbox\"\n[catbox]\nendpoint = \"ftp://x\""),
+            "invalid `catbox.endpoint` in the config file: ftp://x is not an http(s) url"
+        );
+        assert_eq!(
+            error("service = \"catbox\"\noutput = \"template\"\ntemplate = \"{link}\"")
+                .split(':')
+                .next(),
+            Some("invalid `template` in the config file")
+        );
+    }
+
+    #[test]
+    fn test_config_credential_precedence() {
+        let file =
+            file("[imgur]\nclient_id = \"file_client_id\"\n[catbox]\nuserhash = \"file_hash\"");
+        let env = |name: &str| match name {
+            "RTU_IMGUR_CLIENT_ID" => Some("env_client_id".to_string()),
+            "RTU_CATBOX_USERHASH" => Some(String::new()),
+            _ => None,
+        };
+
+        // the environment beats the config file, empty variables count as unset
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--service", "imgur,catbox"]);
+        let config = Config::from_sources(&cli, &file, env).unwrap();
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("env_client_id")
+        );
+        assert_eq!(
+            config.source("imgur.client_id"),
+            Source::Env("RTU_IMGUR_CLIENT_ID")
+        );
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Catbox),
+            Some("file_hash")
+        );
+        assert_eq!(
+            config.source("catbox.userhash"),
+            Source::File("catbox.userhash".to_string())
+        );
+
+        // the flag beats the environment
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--uid", "cli_client_id"]);
+        let config = Config::from_sources(&cli, &file, env).unwrap();
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("cli_client_id")
+        );
+        assert_eq!(config.source("imgur.client_id"), Source::Cli("uid"));
+
+        let description = config.describe();
+        assert!(description.contains("imgur.client_id = *********t_id (--uid)"));
+        assert!(description
+            .contains("catbox.userhash = *****hash (`catbox.userhash` in the config file)"));
+        assert!(description.contains("width = 256 (default)"));
+        assert!(description.contains("filter = catmull-rom (default)"));
+        assert!(description.contains("output = url (default)"));
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        let file = file(
+            r#"
+            service = "imgur"
+            output = "json"
+
+            [imgur]
+            client_id = "file_client_id"
+
+            [profile.discord]
+            service = "catbox"
+            dims = 256
+            resize = "pad"
+            background = "blur"
+            alpha = "auto"
+            format = "webp"
+
+            [profile.forum]
+            dims = 1024
+
+            [profile.forum.imgur]
+            client_id = "forum_client_id"
+            "#,
+        );
+
+        let mut cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--profile", "discord"]);
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.profile, Some("discord".to_string()));
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Catbox]);
+        assert_eq!(config.image_dimensions, (256, 256));
+        assert_eq!(config.image_format, SupportedImageFormat::Webp);
+        assert_eq!(config.resize_mode, ResizeMode::Pad);
+        assert_eq!(config.background, Background::Blur);
+        assert_eq!(config.alpha, Alpha::Auto);
+        // values the profile leaves alone come from the rest of the file
+        assert_eq!(config.output, OutputFormat::Json);
+        assert_eq!(
+            config.source("format"),
+            Source::File("profile.discord.format".to_string())
+        );
+
+        cli.profile = Some("forum".to_string());
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.services, vec![UploadServiceIdentifier::Imgur]);
+        assert_eq!(config.image_dimensions, (1024, 1024));
+        assert_eq!(config.image_format, SupportedImageFormat::Png);
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("forum_client_id")
+        );
+
+        // flags still win over the profile
+        cli.dims = Some(Dimensions::square(512));
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.image_dimensions, (512, 512));
+
+        // a single side can be overridden
+        cli.height = Some(128);
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.image_dimensions, (512, 128));
+        assert_eq!(config.source("height"), Source::Cli("height"));
+        cli.dims = None;
+        let config = Config::from_sources(&cli, &file, no_env).unwrap();
+        assert_eq!(config.image_dimensions, (1024, 128));
+        cli.height = None;
+
+        let config =
+            Config::from_sources(&Cli::parse_from([env!("CARGO_PKG_NAME")]), &file, no_env)
+                .unwrap();
+        assert_eq!(config.profile, None);
+        assert_eq!(
+            config.client_id(UploadServiceIdentifier::Imgur),
+            Some("file_client_id")
+        );
+
+        // errors inside a profile name the profile's key
+        let file = self::file("[profile.discord]\nservice = \"catbox\"\nformat = \"webp\"\n[profile.discord.imgur]\nformat = \"webp\"");
+        cli.profile = Some("discord".to_string());
+        assert!(matches!(
+            Config::from_sources(&cli, &file, no_env).unwrap_err(),
+            AppError::Config(e) if e.starts_with("invalid `profile.discord.imgur.format` in the config file")
+        ));
+
+        cli.profile = Some("chat".to_string());
+        assert!(matches!(
+            Config::from_sources(&cli, &file, no_env).unwrap_err(),
+            AppError::Config(e) if e.starts_with("unknown profile chat")
+        ));
+    }
 }
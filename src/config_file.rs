@@ -0,0 +1,386 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{
+    OutputFormat, PngCompression, PngFilter, ResampleFilter, ResizeMode, SupportedImageFormat,
+    WebpMode,
+};
+use crate::errors::AppError;
+use crate::image_processor::{Alpha, Background, Dimensions, Upscale};
+use crate::uploaders::UploadServiceIdentifier;
+
+static CONFIG_FILENAME: &str = "config.toml";
+static PORTABLE_FILENAME: &str = concat!(env!("CARGO_PKG_NAME"), ".toml");
+
+/// Settings read from the TOML configuration file, every key is optional.
+///
+/// Top-level keys mirror the command line options, the `[imgur]` and `[catbox]`
+/// sections hold credentials and per-service overrides.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub service: Option<Vec<UploadServiceIdentifier>>,
+    pub dims: Option<Dimensions>,
+    pub resize: Option<ResizeMode>,
+    pub filter: Option<ResampleFilter>,
+    pub linear: Option<bool>,
+    pub upscale: Option<Upscale>,
+    pub min_source_size: Option<Dimensions>,
+    pub frame: Option<u32>,
+    pub animate: Option<bool>,
+    pub background: Option<Background>,
+    pub alpha: Option<Alpha>,
+    pub format: Option<SupportedImageFormat>,
+    pub quality: Option<u8>,
+    pub webp: Option<WebpMode>,
+    pub png_compression: Option<PngCompression>,
+    pub png_filter: Option<PngFilter>,
+    pub max_bytes: Option<u64>,
+    pub output: Option<OutputFormat>,
+    pub template: Option<String>,
+    pub folder_art: Option<Vec<String>>,
+    pub cache: Option<PathBuf>,
+    pub no_cache: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub revalidate: Option<bool>,
+    pub retries: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub request_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_file_size: Option<u64>,
+    pub max_source_dims: Option<Dimensions>,
+    pub max_alloc: Option<u64>,
+
+    #[serde(default)]
+    pub imgur: ServiceSection,
+    #[serde(default)]
+    pub catbox: ServiceSection,
+
+    /// Named sets of overrides, `[profile.NAME]`, selected with `--profile`
+    #[serde(default)]
+    pub profile: HashMap<String, Profile>,
+}
+
+/// Settings a profile can override, also the shape of the file's own top-level values
+/// when they are layered under a profile
+#[derive(Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub service: Option<Vec<UploadServiceIdentifier>>,
+    pub dims: Option<Dimensions>,
+    pub resize: Option<ResizeMode>,
+    pub filter: Option<ResampleFilter>,
+    pub linear: Option<bool>,
+    pub upscale: Option<Upscale>,
+    pub min_source_size: Option<Dimensions>,
+    pub frame: Option<u32>,
+    pub animate: Option<bool>,
+    pub background: Option<Background>,
+    pub alpha: Option<Alpha>,
+    pub format: Option<SupportedImageFormat>,
+    pub quality: Option<u8>,
+    pub webp: Option<WebpMode>,
+    pub png_compression: Option<PngCompression>,
+    pub png_filter: Option<PngFilter>,
+    pub max_bytes: Option<u64>,
+    pub output: Option<OutputFormat>,
+    pub template: Option<String>,
+
+    #[serde(default)]
+    pub imgur: ServiceSection,
+    #[serde(default)]
+    pub catbox: ServiceSection,
+}
+
+impl Profile {
+    pub fn section(&self, service: UploadServiceIdentifier) -> &ServiceSection {
+        match service {
+            UploadServiceIdentifier::Imgur => &self.imgur,
+            UploadServiceIdentifier::Catbox => &self.catbox,
+        }
+    }
+}
+
+/// Per-service settings, used when the service is part of the chain
+#[derive(Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceSection {
+    /// Client id for imgur, user hash for catbox
+    #[serde(alias = "userhash")]
+    pub client_id: Option<String>,
+    /// Preferred format for this service
+    pub format: Option<SupportedImageFormat>,
+    /// Thumbnail box when this service is the first in the chain
+    pub dims: Option<Dimensions>,
+    /// Replaces the upload url, e.g. for a self-hosted instance
+    pub endpoint: Option<String>,
+}
+
+/// `service = "imgur"` and `service = ["imgur", "catbox"]` are both accepted
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<UploadServiceIdentifier>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(UploadServiceIdentifier),
+        Many(Vec<UploadServiceIdentifier>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(service)) => Some(vec![service]),
+        Some(OneOrMany::Many(services)) => Some(services),
+        None => None,
+    })
+}
+
+impl ConfigFile {
+    /// Locations searched when no explicit path is given, in order: next to the executable
+    /// (for portable foobar2000 installs), then the platform config directory
+    pub fn search_paths() -> Vec<PathBuf> {
+        let next_to_exe = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join(PORTABLE_FILENAME)));
+        let config_dir =
+            dirs::config_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(CONFIG_FILENAME));
+
+        next_to_exe.into_iter().chain(config_dir).collect()
+    }
+
+    /// Loads the explicit file, or the first one found in `search_paths`.
+    /// No file at all is not an error, an explicit path that does not exist is.
+    pub fn load(explicit: Option<&Path>) -> Result<Self, AppError> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match Self::search_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+
+        let text = fs::read_to_string(&path).map_err(|e| {
+            AppError::Config(format!("cannot read {}: {}", path.to_string_lossy(), e))
+        })?;
+
+        Self::parse(&text)
+            .map_err(|e| AppError::Config(format!("{}: {}", path.to_string_lossy(), e)))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, AppError> {
+        // values are checked after merging, in `Config::valid`
+        toml::from_str(text).map_err(|e| {
+            let line = e
+                .span()
+                .map(|span| text[..span.start].matches('\n').count() + 1);
+            match line {
+                Some(line) => AppError::Config(format!("line {}: {}", line, e.message())),
+                None => AppError::Config(e.message().to_string()),
+            }
+        })
+    }
+
+    pub fn section(&self, service: UploadServiceIdentifier) -> &ServiceSection {
+        match service {
+            UploadServiceIdentifier::Imgur => &self.imgur,
+            UploadServiceIdentifier::Catbox => &self.catbox,
+        }
+    }
+
+    /// The profile-overridable values in order of precedence, each with the key prefix it was
+    /// written under: the selected profile first, then the file's top level
+    pub fn layers(&self, profile: Option<&str>) -> Result<Vec<(String, Profile)>, AppError> {
+        let base = Profile {
+            service: self.service.clone(),
+            dims: self.dims,
+            resize: self.resize,
+            filter: self.filter,
+            linear: self.linear,
+            upscale: self.upscale,
+            min_source_size: self.min_source_size,
+            frame: self.frame,
+            animate: self.animate,
+            background: self.background,
+            alpha: self.alpha,
+            format: self.format,
+            quality: self.quality,
+            webp: self.webp,
+            png_compression: self.png_compression,
+            png_filter: self.png_filter,
+            max_bytes: self.max_bytes,
+            output: self.output,
+            template: self.template.clone(),
+            imgur: self.imgur.clone(),
+            catbox: self.catbox.clone(),
+        };
+
+        let Some(name) = profile else {
+            return Ok(vec![(String::new(), base)]);
+        };
+
+        let Some(selected) = self.profile.get(name) else {
+            let mut known: Vec<&str> = self.profile.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            if known.is_empty() {
+                return Err(AppError::Config(format!(
+                    "unknown profile {}, the config file defines none",
+                    name
+                )));
+            }
+            return Err(AppError::Config(format!(
+                "unknown profile {}, expected one of: {}",
+                name,
+                known.join(", ")
+            )));
+        };
+
+        Ok(vec![
+            (format!("profile.{}.", name), selected.clone()),
+            (String::new(), base),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_full_file() {
+        let file = ConfigFile::parse(
+            r#"
+            service = ["catbox", "imgur"]
+            dims = 512
+            format = "webp"
+            quality = 90
+            webp = "lossy"
+            output = "json"
+            folder_art = ["folder", "cover"]
+            retries = 4
+            timeout = 20
+
+            [imgur]
+            client_id = "abc"
+            dims = "1024x512"
+
+            [catbox]
+            userhash = "hash"
+            endpoint = "https://catbox.example/user/api.php"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            file.service,
+            Some(vec![
+                UploadServiceIdentifier::Catbox,
+                UploadServiceIdentifier::Imgur
+            ])
+        );
+        assert_eq!(file.dims, Some(Dimensions::square(512)));
+        assert_eq!(file.format, Some(SupportedImageFormat::Webp));
+        assert_eq!(file.quality, Some(90));
+        assert_eq!(file.webp, Some(WebpMode::Lossy));
+        assert_eq!(file.output, Some(OutputFormat::Json));
+        assert_eq!(file.retries, Some(4));
+        assert_eq!(file.imgur.client_id, Some("abc".to_string()));
+        assert_eq!(
+            file.imgur.dims,
+            Some(Dimensions {
+                width: 1024,
+                height: 512
+            })
+        );
+        assert_eq!(file.catbox.client_id, Some("hash".to_string()));
+        assert_eq!(
+            file.catbox.endpoint,
+            Some("https://catbox.example/user/api.php".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_single_service() {
+        let file = ConfigFile::parse(r#"service = "catbox""#).unwrap();
+        assert_eq!(file.service, Some(vec![UploadServiceIdentifier::Catbox]));
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_keys() {
+        let error = |text: &str| match ConfigFile::parse(text).unwrap_err() {
+            AppError::Config(message) => message,
+            e => panic!("unexpected error {}", e),
+        };
+
+        assert!(error("dimz = 3").contains("dimz"));
+        assert!(error("[catbox]\nclientid = \"x\"").contains("clientid"));
+        assert!(error("format = \"bmp\"").contains("bmp"));
+        assert!(error("background = \"red\"").contains("invalid background red"));
+        assert!(error("dims = \"wide\"").contains("invalid dimensions wide"));
+        assert!(error("upscale = \"allow-up-to-0.5x\"").contains("at least 1"));
+    }
+
+    #[test]
+    fn test_load_explicit_path() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join(CONFIG_FILENAME);
+        fs::write(&path, "dims = 128").unwrap();
+
+        assert_eq!(
+            ConfigFile::load(Some(&path)).unwrap().dims,
+            Some(Dimensions::square(128))
+        );
+
+        let missing = temp_dir.path().join("missing.toml");
+        assert!(matches!(
+            ConfigFile::load(Some(&missing)).unwrap_err(),
+            AppError::Config(_)
+        ));
+    }
+
+    #[test]
+    fn test_profile_layers() {
+        let file = ConfigFile::parse(
+            r#"
+            dims = 256
+
+            [profile.forum]
+            service = "imgur"
+            dims = 1024
+            output = "template"
+            template = "[img]{url}[/img]"
+
+            [profile.forum.imgur]
+            client_id = "forum_client_id"
+
+            [profile.discord]
+            service = ["catbox"]
+            format = "webp"
+            "#,
+        )
+        .unwrap();
+
+        let layers = file.layers(None).unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].1.dims, Some(Dimensions::square(256)));
+
+        let layers = file.layers(Some("forum")).unwrap();
+        assert_eq!(layers[0].0, "profile.forum.");
+        assert_eq!(layers[0].1.dims, Some(Dimensions::square(1024)));
+        assert_eq!(
+            layers[0].1.imgur.client_id,
+            Some("forum_client_id".to_string())
+        );
+        assert_eq!(layers[1].0, "");
+
+        assert!(matches!(
+            file.layers(Some("chat")).unwrap_err(),
+            AppError::Config(e) if e == "unknown profile chat, expected one of: discord, forum"
+        ));
+    }
+}
@@ -1,5 +1,8 @@
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::image_processor::Dimensions;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("IO error: {0}")]
@@ -11,12 +14,50 @@ pub enum AppError {
     #[error("Upload error: {0}")]
     Upload(String),
 
+    #[error("{service} API error ({status}): {message}")]
+    Api {
+        service: String,
+        status: u16,
+        message: String,
+        /// Wait requested by the server through Retry-After or rate limit headers
+        retry_after: Option<Duration>,
+    },
+
+    #[error("Every upload service failed: {}", .0.join("; "))]
+    AllServicesFailed(Vec<String>),
+
     #[error("File not found: {0}")]
     FileNotFound(String),
 
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
 
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Source image exceeds the decode limits: {0}")]
+    DecodeLimit(String),
+
+    #[error("Source image is {size}, smaller than the minimum of {min}")]
+    SourceTooSmall { size: Dimensions, min: Dimensions },
+
+    #[error(
+        "Thumbnail does not fit in {max_bytes} bytes, the smallest attempt was {smallest} bytes"
+    )]
+    TooLarge { max_bytes: u64, smallest: u64 },
+
     #[error("Configuration error: {0}")]
     Config(String),
+
+    #[error("Artwork extraction error: {0}")]
+    Artwork(String),
+
+    #[error("No artwork found in {0}")]
+    NoArtwork(String),
+
+    #[error("Cache error: {0}")]
+    Cache(String),
+
+    #[error("Output error: {0}")]
+    Output(String),
 }
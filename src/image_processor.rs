@@ -1,105 +1,2682 @@
+use crate::cli::{PngCompression, PngFilter, ResizeMode, WebpMode};
 use crate::errors::AppError;
-use image::{self, ImageFormat};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::error::{EncodingError, ImageFormatHint, LimitErrorKind};
+use image::imageops::{self, FilterType};
+use image::metadata::Orientation;
+use image::{
+    self, AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageDecoder, ImageError,
+    ImageFormat, ImageReader, Limits, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use serde::Deserialize;
+use std::fmt;
 use std::io::Cursor;
+use std::str::FromStr;
+use zune_core::colorspace::ColorSpace;
+use zune_core::options::DecoderOptions;
+use zune_jpeg::JpegDecoder;
 
 pub struct ImageProcessingOptions {
-    pub size: u32,
+    pub size: Dimensions,
     pub format: ImageFormat,
+    pub encoding: Encoding,
+    pub resampling: Resampling,
+    pub upscale: Upscale,
+    /// Sources smaller than this on either side are rejected instead of thumbnailed
+    pub min_source_size: Option<Dimensions>,
+    /// Upper bound for the encoded size, quality and then dimensions are lowered to meet it
+    pub max_bytes: Option<u64>,
+    pub mode: ResizeMode,
+    /// What fills the borders in `ResizeMode::Pad`
+    pub background: Background,
+    pub animation: Animation,
+    pub alpha: Alpha,
+    pub limits: DecodeLimits,
 }
 
+impl Default for ImageProcessingOptions {
+    fn default() -> Self {
+        ImageProcessingOptions {
+            size: Dimensions::square(256),
+            format: ImageFormat::Png,
+            encoding: Encoding::default(),
+            resampling: Resampling::default(),
+            upscale: Upscale::Allow,
+            min_source_size: None,
+            max_bytes: None,
+            mode: ResizeMode::Fit,
+            background: Background::default(),
+            animation: Animation::default(),
+            alpha: Alpha::Keep,
+            limits: DecodeLimits::default(),
+        }
+    }
+}
+
+/// Bounds on the sources that are decoded, checked before their pixels are read so that a huge
+/// or crafted file fails early instead of exhausting memory
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DecodeLimits {
+    /// Largest image file or embedded picture, in bytes
+    pub max_file_size: u64,
+    /// Largest width and height of a source
+    pub max_dimensions: Dimensions,
+    /// Most memory decoding may take, the decoded pixels included
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_file_size: 64 * 1024 * 1024,
+            max_dimensions: Dimensions::square(16384),
+            max_alloc: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    pub fn check_file_size(&self, size: u64) -> Result<(), AppError> {
+        if size > self.max_file_size {
+            return Err(AppError::DecodeLimit(format!(
+                "the file is {} bytes, at most {} are allowed",
+                size, self.max_file_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the size a decoder read from the image header, before anything is decoded
+    fn check_header(&self, decoder: &impl ImageDecoder) -> Result<(), AppError> {
+        let (width, height) = decoder.dimensions();
+        if width > self.max_dimensions.width || height > self.max_dimensions.height {
+            return Err(AppError::DecodeLimit(format!(
+                "the image is {}x{}, at most {} is allowed",
+                width, height, self.max_dimensions
+            )));
+        }
+
+        self.check_alloc(decoder.total_bytes())
+    }
+
+    fn check_alloc(&self, bytes: u64) -> Result<(), AppError> {
+        if bytes > self.max_alloc {
+            return Err(AppError::DecodeLimit(format!(
+                "decoding needs {} bytes, at most {} are allowed",
+                bytes, self.max_alloc
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Limits for the decoders themselves, which bound their working buffers with them
+    fn to_limits(self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+
+    /// A decoder going over its `Limits` on its own is reported like the checks made up front
+    fn error(&self, e: ImageError) -> AppError {
+        match e {
+            ImageError::Limits(e) if e.kind() == LimitErrorKind::InsufficientMemory => {
+                AppError::DecodeLimit(format!(
+                    "decoding needs more than the {} bytes allowed",
+                    self.max_alloc
+                ))
+            }
+            ImageError::Limits(e) => AppError::DecodeLimit(e.to_string()),
+            e => AppError::Image(e),
+        }
+    }
+}
+
+/// What is made of an animated source (GIF, APNG or WebP)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Animation {
+    /// A still of this frame, counting from 1, or of the last one when the source is shorter
+    Frame(u32),
+    /// Every frame resized with its delay kept, for formats that can animate
+    Animate,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Animation::Frame(1)
+    }
+}
+
+/// Whether `format` can hold an animated thumbnail
+pub fn can_animate(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Gif | ImageFormat::WebP)
+}
+
+/// How pixels are resampled when the image is scaled
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Resampling {
+    pub filter: FilterType,
+    /// Average in linear light instead of on gamma-encoded values, keeps dark covers from
+    /// turning muddy at the cost of a slower resize
+    pub linear: bool,
+}
+
+impl Default for Resampling {
+    fn default() -> Self {
+        Resampling {
+            filter: FilterType::CatmullRom,
+            linear: false,
+        }
+    }
+}
+
+/// How far a source may be scaled up, written as `never`, `allow` or `allow-up-to-2x`
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Upscale {
+    Never,
+    Allow,
+    /// At most by this factor
+    UpTo(f32),
+}
+
+impl Upscale {
+    /// `size` shrunk on each axis so that `source` is not scaled up more than allowed
+    pub fn cap(self, size: Dimensions, source: (u32, u32)) -> Dimensions {
+        let factor = match self {
+            Upscale::Never => 1.0,
+            Upscale::Allow => return size,
+            Upscale::UpTo(factor) => factor,
+        };
+        let limit = |side: u32| ((side as f32 * factor).floor() as u32).max(1);
+
+        Dimensions {
+            width: size.width.min(limit(source.0)),
+            height: size.height.min(limit(source.1)),
+        }
+    }
+}
+
+impl FromStr for Upscale {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid upscale policy {}, expected never, allow or allow-up-to-2x",
+                value
+            )
+        };
+
+        match value.to_ascii_lowercase().as_str() {
+            "never" => Ok(Upscale::Never),
+            "allow" => Ok(Upscale::Allow),
+            policy => {
+                let factor = policy
+                    .strip_prefix("allow-up-to-")
+                    .and_then(|rest| rest.strip_suffix(['x', '×']))
+                    .and_then(|factor| factor.parse::<f32>().ok())
+                    .ok_or_else(invalid)?;
+
+                if !(factor >= 1.0 && factor.is_finite()) {
+                    return Err(format!(
+                        "invalid upscale policy {}, the factor must be at least 1",
+                        value
+                    ));
+                }
+                Ok(Upscale::UpTo(factor))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Upscale {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Upscale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upscale::Never => write!(f, "never"),
+            Upscale::Allow => write!(f, "allow"),
+            Upscale::UpTo(factor) => write!(f, "allow-up-to-{}x", factor),
+        }
+    }
+}
+
+/// Encoder settings, each only applies to some of the formats
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Encoding {
+    /// Quality of lossy formats, 1-100
+    pub quality: u8,
+    pub webp: WebpMode,
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+}
+
+impl Encoding {
+    /// Whether `format` is encoded lossily, the only case `quality` is used
+    pub fn is_lossy(&self, format: ImageFormat) -> bool {
+        match format {
+            ImageFormat::Jpeg | ImageFormat::Avif => true,
+            ImageFormat::WebP => self.webp == WebpMode::Lossy,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            quality: 85,
+            webp: WebpMode::Lossless,
+            png_compression: PngCompression::Fast,
+            png_filter: PngFilter::Adaptive,
+        }
+    }
+}
+
+/// Target size of a thumbnail, written as `256` for a square or `512x256`
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(try_from = "DimensionsValue")]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Dimensions {
+    pub fn square(size: u32) -> Self {
+        Dimensions {
+            width: size,
+            height: size,
+        }
+    }
+}
+
+impl FromStr for Dimensions {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid dimensions {}, expected 256 or 512x256", value);
+        let number = |n: &str| n.trim().parse::<u32>().map_err(|_| invalid());
+
+        match value.split_once(['x', 'X']) {
+            Some((width, height)) => Ok(Dimensions {
+                width: number(width)?,
+                height: number(height)?,
+            }),
+            None => Ok(Dimensions::square(number(value)?)),
+        }
+    }
+}
+
+impl fmt::Display for Dimensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// `dims = 256` and `dims = "512x256"` in the config file
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DimensionsValue {
+    Size(u32),
+    Text(String),
+}
+
+impl TryFrom<DimensionsValue> for Dimensions {
+    type Error = String;
+
+    fn try_from(value: DimensionsValue) -> Result<Self, Self::Error> {
+        match value {
+            DimensionsValue::Size(size) => Ok(Dimensions::square(size)),
+            DimensionsValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+/// Backdrop for padded thumbnails, written as `#rrggbb`, `#rrggbbaa` or `blur`
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Background {
+    Color(Rgba<u8>),
+    /// The image itself, scaled to fill the square and blurred
+    Blur,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color(Rgba([0, 0, 0, 255]))
+    }
+}
+
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("blur") {
+            return Ok(Background::Blur);
+        }
+
+        let invalid = || {
+            format!(
+                "invalid background {}, expected #rrggbb, #rrggbbaa or blur",
+                value
+            )
+        };
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut channels = [255; 4];
+        for (i, channel) in channels.iter_mut().take(hex.len() / 2).enumerate() {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Background::Color(Rgba(channels)))
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Blur => write!(f, "blur"),
+            Background::Color(Rgba([r, g, b, 255])) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            Background::Color(Rgba([r, g, b, a])) => {
+                write!(f, "#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+            }
+        }
+    }
+}
+
+/// What becomes of transparency, written as `keep`, `auto` or a `#rrggbb` colour to flatten onto
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Alpha {
+    /// Transparent pixels stay transparent, except in JPEG thumbnails which are flattened onto white
+    #[default]
+    Keep,
+    Flatten(Rgb<u8>),
+    /// Flatten onto a colour picked to go with the image, see `auto_matte`
+    Auto,
+}
+
+impl FromStr for Alpha {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("keep") {
+            return Ok(Alpha::Keep);
+        }
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(Alpha::Auto);
+        }
+
+        // colours are written like backgrounds, but must be opaque
+        match value.parse::<Background>() {
+            Ok(Background::Color(Rgba([r, g, b, 255]))) => Ok(Alpha::Flatten(Rgb([r, g, b]))),
+            _ => Err(format!(
+                "invalid alpha {}, expected keep, auto or #rrggbb",
+                value
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Alpha {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Alpha {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alpha::Keep => write!(f, "keep"),
+            Alpha::Auto => write!(f, "auto"),
+            Alpha::Flatten(Rgb([r, g, b])) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+        }
+    }
+}
+
+/// An encoded thumbnail, holding pixels only: no EXIF, XMP or ICC profile is written
+#[derive(Clone)]
 pub struct ProcessedImage {
     pub data: Vec<u8>,
     pub format: ImageFormat,
+    /// Settings the thumbnail was encoded with, reused when it is converted to another format
+    pub encoding: Encoding,
+    pub width: u32,
+    pub height: u32,
+    /// Whether `data` holds more than one frame
+    pub animated: bool,
+    /// Size budget the thumbnail was made under, also met when it is converted
+    pub max_bytes: Option<u64>,
+}
+
+/// A decoded source, a single image or every frame of an animation
+enum Picture {
+    Still(DynamicImage),
+    Animation(Vec<Frame>),
+}
+
+impl Picture {
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Picture::Still(img) => img.dimensions(),
+            Picture::Animation(frames) => frames[0].buffer().dimensions(),
+        }
+    }
+
+    /// Colour transparent pixels are flattened onto under `alpha`, `None` when they are kept
+    fn matte(&self, alpha: Alpha) -> Option<Rgb<u8>> {
+        match (alpha, self) {
+            (Alpha::Keep, _) => None,
+            (Alpha::Flatten(color), _) => Some(color),
+            (Alpha::Auto, Picture::Still(img)) => Some(auto_matte(&img.to_rgba8())),
+            (Alpha::Auto, Picture::Animation(frames)) => Some(auto_matte(frames[0].buffer())),
+        }
+    }
+
+    /// Scales to `size` as `options` say, flattening onto `matte` when one is given
+    fn resize(
+        &self,
+        size: Dimensions,
+        options: &ImageProcessingOptions,
+        matte: Option<Rgb<u8>>,
+    ) -> Picture {
+        let resize = |img: &DynamicImage, mode| {
+            let img = resize(
+                img,
+                size,
+                mode,
+                options.background,
+                options.resampling,
+                options.upscale,
+            );
+            match matte {
+                // after the resize, so padded borders are flattened as well
+                Some(matte) if img.color().has_alpha() => flatten(&img, matte),
+                _ => img,
+            }
+        };
+
+        match self {
+            Picture::Still(img) => Picture::Still(resize(img, options.mode)),
+            Picture::Animation(frames) => {
+                // a crop that follows the detail of each frame would jitter
+                let mode = match options.mode {
+                    ResizeMode::Smart => ResizeMode::Fill,
+                    mode => mode,
+                };
+
+                Picture::Animation(
+                    frames
+                        .iter()
+                        .map(|frame| {
+                            let img = DynamicImage::ImageRgba8(frame.buffer().clone());
+                            Frame::from_parts(resize(&img, mode).into_rgba8(), 0, 0, frame.delay())
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn encode(&self, format: ImageFormat, encoding: Encoding) -> Result<Vec<u8>, AppError> {
+        match self {
+            Picture::Still(img) => encode(img, format, encoding),
+            Picture::Animation(frames) => encode_animation(frames, format, encoding),
+        }
+    }
 }
 
 pub fn create_thumbnail(
     filepath: &str,
     options: &ImageProcessingOptions,
 ) -> Result<ProcessedImage, AppError> {
-    // Open the image
-    let img = image::open(filepath)?;
+    // refuse oversized files before reading them
+    options
+        .limits
+        .check_file_size(std::fs::metadata(filepath)?.len())?;
+    let data = std::fs::read(filepath)?;
 
-    // Create thumbnail
-    let thumbnail = img.thumbnail(options.size, options.size);
+    create_thumbnail_from_memory(&data, options)
+}
 
-    // Convert to specified format
-    let mut buf = Cursor::new(Vec::new());
-    thumbnail
-        .write_to(&mut buf, options.format)
-        .map_err(|e| AppError::Image(e))?;
+/// Decodes an image upright and in sRGB, applying the rotation or flip recorded in its EXIF
+/// orientation tag and converting colours from an embedded ICC profile.
+///
+/// Neither the tag nor the profile is carried over, thumbnails are encoded without metadata.
+/// Images over `limits` are refused once their header is read.
+pub fn decode(data: &[u8], limits: &DecodeLimits) -> Result<DynamicImage, AppError> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits.to_limits());
+    let format = reader.format();
+    let mut decoder = reader.into_decoder().map_err(|e| limits.error(e))?;
+    limits.check_header(&decoder)?;
 
-    Ok(ProcessedImage {
-        data: buf.into_inner(),
-        format: options.format,
-    })
+    // phones store scans as taken and only record how to turn them
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc = decoder.icc_profile().ok().flatten();
+
+    let mut img = DynamicImage::from_decoder(decoder).map_err(|e| limits.error(e))?;
+    if let Some(icc) = icc {
+        img = to_srgb(img, &icc, data, format);
+    }
+    img.apply_orientation(orientation);
+
+    Ok(img)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use image::{GenericImageView, ImageBuffer, Rgb};
-    use tempfile::TempDir;
+/// Decodes every frame of an animated GIF, APNG or WebP, in sRGB and composited to full size.
+///
+/// Still images, including files of these formats with a single frame, give `None`. The
+/// frames together may take at most the `max_alloc` of `limits`, decoding stops once they do.
+pub fn decode_frames(data: &[u8], limits: &DecodeLimits) -> Result<Option<Vec<Frame>>, AppError> {
+    let format = image::guess_format(data).ok();
+    let cursor = Cursor::new(data);
+    let (animation, icc) = match format {
+        Some(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(cursor)?;
+            decoder
+                .set_limits(limits.to_limits())
+                .map_err(|e| limits.error(e))?;
+            limits.check_header(&decoder)?;
+            let icc = decoder.icc_profile().ok().flatten();
+            (decoder.into_frames(), icc)
+        }
+        Some(ImageFormat::Png) => {
+            let mut decoder =
+                PngDecoder::with_limits(cursor, limits.to_limits()).map_err(|e| limits.error(e))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            limits.check_header(&decoder)?;
+            let icc = decoder.icc_profile().ok().flatten();
+            (decoder.apng()?.into_frames(), icc)
+        }
+        Some(ImageFormat::WebP) => {
+            let mut decoder = WebPDecoder::new(cursor)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder
+                .set_limits(limits.to_limits())
+                .map_err(|e| limits.error(e))?;
+            limits.check_header(&decoder)?;
+            let icc = decoder.icc_profile().ok().flatten();
+            (decoder.into_frames(), icc)
+        }
+        _ => return Ok(None),
+    };
 
-    fn create_test_image(width: u32, height: u32) -> (TempDir, String) {
-        let temp_dir = TempDir::new().unwrap();
-        let file_path = temp_dir.path().join("test_image.png");
+    let mut frames = Vec::new();
+    let mut allocated = 0;
+    for frame in animation {
+        let frame = frame.map_err(|e| limits.error(e))?;
+        allocated += frame.buffer().as_raw().len() as u64;
+        limits.check_alloc(allocated)?;
+        frames.push(frame);
+    }
+    if frames.len() < 2 {
+        return Ok(None);
+    }
 
-        let mut img = ImageBuffer::new(width, height);
+    let Some(icc) = icc else {
+        return Ok(Some(frames));
+    };
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let img = to_srgb(
+                DynamicImage::ImageRgba8(frame.into_buffer()),
+                &icc,
+                data,
+                format,
+            );
+            Frame::from_parts(img.into_rgba8(), 0, 0, delay)
+        })
+        .collect();
 
-        // Fill with a simple pattern
-        for (x, y, pixel) in img.enumerate_pixels_mut() {
-            *pixel = Rgb([x as u8, y as u8, 100]);
+    Ok(Some(frames))
+}
+
+/// Converts `img` from the colour space described by the ICC profile `icc` to sRGB.
+///
+/// Untagged images are taken to be sRGB already. Profiles that cannot be read or applied
+/// leave the image as decoded, a slight colour shift beats failing the upload.
+fn to_srgb(
+    img: DynamicImage,
+    icc: &[u8],
+    data: &[u8],
+    format: Option<ImageFormat>,
+) -> DynamicImage {
+    let Some(profile) = qcms::Profile::new_from_slice(icc, false) else {
+        return img;
+    };
+    if profile.is_sRGB() {
+        return img;
+    }
+
+    let srgb = qcms::Profile::new_sRGB();
+    let intent = qcms::Intent::default();
+
+    // the data colour space signature sits at offset 16 of the profile header
+    match icc.get(16..20) {
+        Some(b"RGB ") => {
+            if img.color().has_alpha() {
+                let mut rgba = img.into_rgba8();
+                if let Some(transform) =
+                    qcms::Transform::new(&profile, &srgb, qcms::DataType::RGBA8, intent)
+                {
+                    transform.apply(&mut rgba);
+                }
+                DynamicImage::ImageRgba8(rgba)
+            } else {
+                let mut rgb = img.into_rgb8();
+                if let Some(transform) =
+                    qcms::Transform::new(&profile, &srgb, qcms::DataType::RGB8, intent)
+                {
+                    transform.apply(&mut rgb);
+                }
+                DynamicImage::ImageRgb8(rgb)
+            }
+        }
+        Some(b"GRAY") if !img.color().has_alpha() => {
+            let luma = img.to_luma8();
+            let mut rgb = RgbImage::new(luma.width(), luma.height());
+            match qcms::Transform::new_to(
+                &profile,
+                &srgb,
+                qcms::DataType::Gray8,
+                qcms::DataType::RGB8,
+                intent,
+            ) {
+                Some(transform) => transform.convert(&luma, &mut rgb),
+                None => return img,
+            }
+            DynamicImage::ImageRgb8(rgb)
+        }
+        // the image decoder already turned the inks into RGB naively, decode them again
+        Some(b"CMYK") if format == Some(ImageFormat::Jpeg) => {
+            let Some((width, height, cmyk)) = decode_cmyk(data) else {
+                return img;
+            };
+            let mut rgb = RgbImage::new(width, height);
+            match qcms::Transform::new_to(
+                &profile,
+                &srgb,
+                qcms::DataType::CMYK,
+                qcms::DataType::RGB8,
+                intent,
+            ) {
+                Some(transform) => transform.convert(&cmyk, &mut rgb),
+                None => return img,
+            }
+            DynamicImage::ImageRgb8(rgb)
         }
+        _ => img,
+    }
+}
 
-        img.save(&file_path).unwrap();
+/// The raw inks of a CMYK JPEG as `(width, height, cmyk)`, `None` for any other JPEG
+fn decode_cmyk(data: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    decoder.decode_headers().ok()?;
+    if decoder.get_input_colorspace()? != ColorSpace::CMYK {
+        return None;
+    }
 
-        (temp_dir, file_path.to_string_lossy().to_string())
+    let mut cmyk = decoder.decode().ok()?;
+    let (width, height) = decoder.dimensions()?;
+
+    // Adobe software, which writes nearly all CMYK JPEGs, stores inverted inks with 255
+    // meaning none and marks the file with an APP14 segment
+    let adobe = data
+        .windows(9)
+        .any(|segment| segment[..2] == [0xFF, 0xEE] && &segment[4..] == b"Adobe");
+    if adobe {
+        for ink in &mut cmyk {
+            *ink = 255 - *ink;
+        }
     }
 
-    #[test]
-    fn test_create_thumbnail_resizes_correctly() {
-        let (temp_dir, file_path) = create_test_image(200, 200);
+    Some((width as u32, height as u32, cmyk))
+}
 
-        let options = ImageProcessingOptions {
-            size: 100,
-            format: ImageFormat::Png,
-        };
+pub fn create_thumbnail_from_memory(
+    data: &[u8],
+    options: &ImageProcessingOptions,
+) -> Result<ProcessedImage, AppError> {
+    // Decode the image, the format is guessed from its contents
+    options.limits.check_file_size(data.len() as u64)?;
+    let frames = match options.animation {
+        Animation::Frame(1) => None,
+        _ => decode_frames(data, &options.limits)?,
+    };
+    let picture = match (frames, options.animation) {
+        (Some(frames), Animation::Animate) if can_animate(options.format) => {
+            Picture::Animation(frames)
+        }
+        (Some(mut frames), Animation::Frame(frame)) => {
+            let index = (frame as usize).clamp(1, frames.len()) - 1;
+            Picture::Still(DynamicImage::ImageRgba8(
+                frames.swap_remove(index).into_buffer(),
+            ))
+        }
+        _ => Picture::Still(decode(data, &options.limits)?),
+    };
 
-        let result = create_thumbnail(&file_path, &options).unwrap();
+    if let Some(min) = options.min_source_size {
+        let (width, height) = picture.dimensions();
+        if width < min.width || height < min.height {
+            return Err(AppError::SourceTooSmall {
+                size: Dimensions { width, height },
+                min,
+            });
+        }
+    }
 
-        // Load the resulting image to verify dimensions
-        let img = image::load_from_memory(&result.data).unwrap();
-        assert_eq!(img.dimensions(), (100, 100));
+    // Create thumbnail and convert to specified format
+    let matte = picture.matte(options.alpha);
+    let (thumbnail, encoding, data) = render(&picture, options, matte)?;
+    let (width, height) = thumbnail.dimensions();
 
-        drop(temp_dir); // Cleanup
+    Ok(ProcessedImage {
+        data,
+        format: options.format,
+        encoding,
+        width,
+        height,
+        animated: matches!(thumbnail, Picture::Animation(_)),
+        max_bytes: options.max_bytes,
+    })
+}
+
+/// Resizes and encodes `picture`, within the size budget of `options` when there is one
+fn render(
+    picture: &Picture,
+    options: &ImageProcessingOptions,
+    matte: Option<Rgb<u8>>,
+) -> Result<(Picture, Encoding, Vec<u8>), AppError> {
+    match options.max_bytes {
+        Some(max_bytes) => fit_budget(picture, options, matte, max_bytes),
+        None => {
+            let thumbnail = picture.resize(options.size, options, matte);
+            let data = thumbnail.encode(options.format, options.encoding)?;
+            Ok((thumbnail, options.encoding, data))
+        }
     }
+}
 
-    #[test]
-    fn test_create_thumbnail_converts_format() {
-        let (temp_dir, file_path) = create_test_image(200, 200);
+/// Lowest quality a size budget may push lossy formats down to
+const MIN_QUALITY: u8 = 20;
+/// Shortest longer side a size budget may shrink the thumbnail to
+const MIN_SIDE: u32 = 32;
 
-        let options = ImageProcessingOptions {
-            size: 100,
-            format: ImageFormat::Jpeg,
+/// Finds the largest, highest quality thumbnail of `picture` that encodes to at most `max_bytes`.
+///
+/// Lossy formats give up quality first, down to `MIN_QUALITY`, found by bisection. When that is
+/// not enough, the box shrinks by a quarter at a time until its longer side would go below
+/// `MIN_SIDE`, and the quality search starts over at each size.
+fn fit_budget(
+    picture: &Picture,
+    options: &ImageProcessingOptions,
+    matte: Option<Rgb<u8>>,
+    max_bytes: u64,
+) -> Result<(Picture, Encoding, Vec<u8>), AppError> {
+    let fits = |data: &[u8]| data.len() as u64 <= max_bytes;
+    let mut size = options.size;
+    let mut smallest = usize::MAX;
+
+    loop {
+        let thumbnail = picture.resize(size, options, matte);
+        let mut encode = |encoding| {
+            thumbnail.encode(options.format, encoding).inspect(|data| {
+                smallest = smallest.min(data.len());
+            })
         };
 
-        let result = create_thumbnail(&file_path, &options).unwrap();
-        assert_eq!(result.format, ImageFormat::Jpeg);
+        // the requested settings are tried as is first, most thumbnails fit
+        let data = encode(options.encoding)?;
+        if fits(&data) {
+            return Ok((thumbnail, options.encoding, data));
+        }
 
-        // Verify the data is actually JPEG
-        assert!(image::guess_format(&result.data).unwrap() == ImageFormat::Jpeg);
+        if options.encoding.is_lossy(options.format) {
+            let mut best = None;
+            let (mut low, mut high) = (
+                MIN_QUALITY.min(options.encoding.quality),
+                options.encoding.quality.saturating_sub(1),
+            );
+            while low <= high {
+                let quality = low + (high - low) / 2;
+                let encoding = Encoding {
+                    quality,
+                    ..options.encoding
+                };
+                let data = encode(encoding)?;
+                if fits(&data) {
+                    best = Some((encoding, data));
+                    low = quality + 1;
+                } else if quality == 0 {
+                    break;
+                } else {
+                    high = quality - 1;
+                }
+            }
 
-        drop(temp_dir); // Cleanup
+            if let Some((encoding, data)) = best {
+                return Ok((thumbnail, encoding, data));
+            }
+        }
+
+        // a quarter off each side, without overflowing on large boxes
+        let next = Dimensions {
+            width: (size.width - size.width / 4).max(1),
+            height: (size.height - size.height / 4).max(1),
+        };
+        if next.width.max(next.height) < MIN_SIDE || next == size {
+            return Err(AppError::TooLarge {
+                max_bytes,
+                smallest: smallest as u64,
+            });
+        }
+        size = next;
     }
+}
 
-    #[test]
-    fn test_create_thumbnail_invalid_file() {
-        let options = ImageProcessingOptions {
-            size: 100,
-            format: ImageFormat::Png,
+/// Scales `img` into a `size` box according to `mode`.
+///
+/// A source too small for the box is only scaled up as far as `upscale` allows, the result is
+/// then smaller than the box, except in pad mode where the borders grow instead.
+pub fn resize(
+    img: &DynamicImage,
+    size: Dimensions,
+    mode: ResizeMode,
+    background: Background,
+    resampling: Resampling,
+    upscale: Upscale,
+) -> DynamicImage {
+    let Dimensions { width, height } = size;
+
+    match mode {
+        // aspect ratio kept, the image fits inside the box
+        ResizeMode::Fit => scale(img, upscale.cap(size, img.dimensions()), false, resampling),
+        ResizeMode::Fill => {
+            let (x, y, crop_width, crop_height) = centre_crop_window(img, size);
+            scale(
+                &img.crop_imm(x, y, crop_width, crop_height),
+                upscale.cap(size, (crop_width, crop_height)),
+                true,
+                resampling,
+            )
+        }
+        ResizeMode::Smart => {
+            let (x, y, crop_width, crop_height) = smart_crop_window(img, size);
+            scale(
+                &img.crop_imm(x, y, crop_width, crop_height),
+                upscale.cap(size, (crop_width, crop_height)),
+                true,
+                resampling,
+            )
+        }
+        ResizeMode::Stretch => scale(img, upscale.cap(size, img.dimensions()), true, resampling),
+        ResizeMode::Pad => {
+            let fitted = scale(img, upscale.cap(size, img.dimensions()), false, resampling);
+            let mut canvas = match background {
+                Background::Color(color) => RgbaImage::from_pixel(width, height, color),
+                Background::Blur => resize(
+                    img,
+                    size,
+                    ResizeMode::Fill,
+                    background,
+                    resampling,
+                    Upscale::Allow,
+                )
+                .resize_exact(width / 4 + 1, height / 4 + 1, FilterType::Triangle)
+                .blur((width.max(height) as f32 / 64.0).max(1.0))
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_rgba8(),
+            };
+
+            let x = (width - fitted.width()) / 2;
+            let y = (height - fitted.height()) / 2;
+            imageops::overlay(&mut canvas, &fitted.to_rgba8(), x.into(), y.into());
+
+            // keep opaque images free of an alpha channel
+            let opaque = !img.color().has_alpha()
+                && !matches!(background, Background::Color(Rgba([_, _, _, a])) if a < 255);
+            if opaque {
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+            } else {
+                DynamicImage::ImageRgba8(canvas)
+            }
+        }
+    }
+}
+
+/// Composites `img` onto the opaque colour `matte`, the result has no alpha channel
+pub fn flatten(img: &DynamicImage, matte: Rgb<u8>) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    DynamicImage::ImageRgb8(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *rgba.get_pixel(x, y);
+        let blend = |channel: u8, matte: u8| {
+            let (channel, matte, a) = (channel as u32, matte as u32, a as u32);
+            ((channel * a + matte * (255 - a) + 127) / 255) as u8
         };
+        Rgb([blend(r, matte[0]), blend(g, matte[1]), blend(b, matte[2])])
+    }))
+}
 
-        let result = create_thumbnail("nonexistent_file.png", &options);
-        assert!(result.is_err());
+/// A matte that goes with the visible pixels of `img`: their average colour, lightened for
+/// dark artwork and darkened for light artwork so that it still stands out
+fn auto_matte(img: &RgbaImage) -> Rgb<u8> {
+    let mut sum = [0u64; 3];
+    let mut weight = 0;
+    for Rgba([r, g, b, a]) in img.pixels() {
+        for (sum, channel) in sum.iter_mut().zip([r, g, b]) {
+            *sum += *channel as u64 * *a as u64;
+        }
+        weight += *a as u64;
+    }
+
+    // nothing is visible, any colour will do
+    if weight == 0 {
+        return Rgb([255, 255, 255]);
+    }
+
+    let mean = sum.map(|sum| sum / weight);
+    let luma = (2126 * mean[0] + 7152 * mean[1] + 722 * mean[2]) / 10000;
+    let target = if luma < 128 { 255 } else { 0 };
+
+    Rgb(mean.map(|channel| ((channel + 3 * target) / 4) as u8))
+}
+
+/// Scales `img` to fit inside `size`, or with `exact` to be exactly `size`
+fn scale(
+    img: &DynamicImage,
+    size: Dimensions,
+    exact: bool,
+    resampling: Resampling,
+) -> DynamicImage {
+    let Dimensions { width, height } = size;
+    let scaled = |img: &DynamicImage| {
+        if exact {
+            img.resize_exact(width, height, resampling.filter)
+        } else {
+            img.resize(width, height, resampling.filter)
+        }
+    };
+
+    if !resampling.linear {
+        return scaled(img);
+    }
+
+    // filters average light, which the encoded values are not proportional to
+    let mut linear = img.to_rgba32f();
+    for pixel in linear.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = srgb_to_linear(*channel);
+        }
+    }
+
+    let mut encoded = scaled(&DynamicImage::ImageRgba32F(linear)).into_rgba32f();
+    for pixel in encoded.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = linear_to_srgb(*channel);
+        }
+    }
+
+    let encoded = DynamicImage::ImageRgba32F(encoded);
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(encoded.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(encoded.to_rgb8())
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    // sharpening filters overshoot, out of range values are clipped
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Size of the largest crop of `img` with the aspect ratio of `size`
+fn crop_size(img: &DynamicImage, size: Dimensions) -> (u32, u32) {
+    let (source_width, source_height) = img.dimensions();
+    let (width, height) = (size.width as u64, size.height as u64);
+
+    if source_width as u64 * height > source_height as u64 * width {
+        let crop = source_height as u64 * width / height;
+        (crop.max(1) as u32, source_height)
+    } else {
+        let crop = source_width as u64 * height / width;
+        (source_width, crop.max(1) as u32)
+    }
+}
+
+/// The largest centred crop with the aspect ratio of `size`, as `(x, y, width, height)`
+pub fn centre_crop_window(img: &DynamicImage, size: Dimensions) -> (u32, u32, u32, u32) {
+    let (source_width, source_height) = img.dimensions();
+    let (crop_width, crop_height) = crop_size(img, size);
+
+    (
+        (source_width - crop_width) / 2,
+        (source_height - crop_height) / 2,
+        crop_width,
+        crop_height,
+    )
+}
+
+/// Longest side of the copy the smart crop is scored on, detail finer than this does not matter
+const SMART_CROP_SCALE: u32 = 256;
+
+/// The crop with the aspect ratio of `size` that holds the most detail, as `(x, y, width, height)`.
+///
+/// Detail is the gradient magnitude of the luma, summed along the axis the crop cannot move on,
+/// so finding the best window is a single pass over the other axis. Flat images and ties keep
+/// the window closest to the centre.
+pub fn smart_crop_window(img: &DynamicImage, size: Dimensions) -> (u32, u32, u32, u32) {
+    let (source_width, source_height) = img.dimensions();
+    let (crop_width, crop_height) = crop_size(img, size);
+    let horizontal = crop_width < source_width;
+    let (slack, length) = if horizontal {
+        (source_width - crop_width, source_width)
+    } else {
+        (source_height - crop_height, source_height)
+    };
+
+    if slack == 0 {
+        return (0, 0, crop_width, crop_height);
+    }
+
+    // score on a small greyscale copy, positions are mapped back afterwards
+    let luma = img
+        .resize(SMART_CROP_SCALE, SMART_CROP_SCALE, FilterType::Triangle)
+        .to_luma8();
+    let (luma_width, luma_height) = luma.dimensions();
+
+    let scaled_length = if horizontal { luma_width } else { luma_height } as usize;
+    let mut profile = vec![0u64; scaled_length];
+    for y in 0..luma_height {
+        for x in 0..luma_width {
+            let value = luma.get_pixel(x, y)[0] as i32;
+            let right = luma.get_pixel((x + 1).min(luma_width - 1), y)[0] as i32;
+            let below = luma.get_pixel(x, (y + 1).min(luma_height - 1))[0] as i32;
+            let energy = (value - right).unsigned_abs() + (value - below).unsigned_abs();
+
+            let position = if horizontal { x } else { y };
+            profile[position as usize] += energy as u64;
+        }
+    }
+
+    let mut prefix = vec![0u64; scaled_length + 1];
+    for (i, energy) in profile.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + energy;
+    }
+
+    let window = ((scaled_length as u64 * (length - slack) as u64) / length as u64).max(1) as usize;
+    let centre = (scaled_length - window) / 2;
+
+    let best = (0..=scaled_length - window)
+        .max_by_key(|&start| {
+            let score = prefix[start + window] - prefix[start];
+            // prefer the centre when scores tie
+            (score, std::cmp::Reverse(start.abs_diff(centre)))
+        })
+        .unwrap_or(centre);
+
+    // scaling positions back loses precision, keep an exact centre crop when the centre won
+    if best == centre {
+        return centre_crop_window(img, size);
+    }
+
+    let offset = ((best as u64 * length as u64) / scaled_length as u64).min(slack as u64) as u32;
+    if horizontal {
+        (offset, 0, crop_width, crop_height)
+    } else {
+        (0, offset, crop_width, crop_height)
+    }
+}
+
+/// Encodes `img` as `format` with the settings of `encoding` that apply to it
+pub fn encode(
+    img: &DynamicImage,
+    format: ImageFormat,
+    encoding: Encoding,
+) -> Result<Vec<u8>, AppError> {
+    let quality = encoding.quality;
+    let mut buf = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut buf, quality);
+            match img {
+                DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => {
+                    img.write_with_encoder(encoder)?
+                }
+                // JPEG has no alpha channel and only 8 bits, what `Alpha::Keep` left goes onto white
+                img => flatten(img, Rgb([255, 255, 255])).write_with_encoder(encoder)?,
+            }
+        }
+        // the slowest speeds gain little at thumbnail sizes
+        ImageFormat::Avif => {
+            img.write_with_encoder(AvifEncoder::new_with_speed_quality(&mut buf, 8, quality))?
+        }
+        ImageFormat::Png => img.write_with_encoder(PngEncoder::new_with_quality(
+            &mut buf,
+            encoding.png_compression.to_compression_type(),
+            encoding.png_filter.to_filter_type(),
+        ))?,
+        // the image crate only writes lossless WebP
+        ImageFormat::WebP if encoding.webp == WebpMode::Lossy => {
+            let (width, height) = img.dimensions();
+            let memory = if img.color().has_alpha() {
+                webp::Encoder::from_rgba(&img.to_rgba8(), width, height).encode(quality as f32)
+            } else {
+                webp::Encoder::from_rgb(&img.to_rgb8(), width, height).encode(quality as f32)
+            };
+            buf.extend_from_slice(&memory);
+        }
+        format => img.write_to(&mut Cursor::new(&mut buf), format)?,
+    }
+
+    Ok(buf)
+}
+
+/// Encodes the frames of an animation as an animated GIF or WebP, other formats get the first
+/// frame. Animations loop forever.
+fn encode_animation(
+    frames: &[Frame],
+    format: ImageFormat,
+    encoding: Encoding,
+) -> Result<Vec<u8>, AppError> {
+    let mut buf = Vec::new();
+    match format {
+        ImageFormat::Gif => {
+            // a palette is built for every frame, the default speed takes seconds for long ones
+            let mut encoder = GifEncoder::new_with_speed(&mut buf, 10);
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames.iter().cloned())?;
+        }
+        // the image crate cannot write animated WebP
+        ImageFormat::WebP => {
+            let webp_error = |message: String| {
+                AppError::Image(ImageError::Encoding(EncodingError::new(
+                    ImageFormatHint::Exact(ImageFormat::WebP),
+                    message,
+                )))
+            };
+
+            let mut config = webp::WebPConfig::new()
+                .map_err(|_| webp_error("invalid encoder configuration".to_string()))?;
+            config.lossless = (encoding.webp == WebpMode::Lossless) as i32;
+            config.quality = encoding.quality as f32;
+
+            let (width, height) = frames[0].buffer().dimensions();
+            let mut encoder = webp::AnimEncoder::new(width, height, &config);
+            let mut timestamp = 0;
+            for frame in frames {
+                encoder.add_frame(webp::AnimFrame::from_rgba(
+                    frame.buffer(),
+                    width,
+                    height,
+                    timestamp as i32,
+                ));
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                timestamp += numerator / denominator.max(1);
+            }
+
+            let memory = encoder
+                .try_encode()
+                .map_err(|e| webp_error(format!("{:?}", e)))?;
+            buf.extend_from_slice(&memory);
+            set_last_frame_duration(&mut buf, timestamp);
+        }
+        format => {
+            let img = DynamicImage::ImageRgba8(frames[0].buffer().clone());
+            return encode(&img, format, encoding);
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Sets the duration of the last frame of an animated WebP so that all frames last `total` ms.
+///
+/// libwebp is only given the time each frame starts at and makes the last one as long as the
+/// average of the others, this restores its own delay.
+fn set_last_frame_duration(webp: &mut [u8], total: u32) {
+    // chunks follow the 12 byte RIFF header, ANMF holds a 24-bit duration at offset 12
+    let mut offset = 12;
+    let mut durations = 0;
+    let mut last = None;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes([
+            webp[offset + 4],
+            webp[offset + 5],
+            webp[offset + 6],
+            webp[offset + 7],
+        ]) as usize;
+        let duration = offset + 8 + 12;
+        if &webp[offset..offset + 4] == b"ANMF" && duration + 3 <= webp.len() {
+            let value =
+                u32::from_le_bytes([webp[duration], webp[duration + 1], webp[duration + 2], 0]);
+            durations += value;
+            last = Some((duration, value));
+        }
+        // chunks are padded to an even length
+        offset += 8 + size + size % 2;
+    }
+
+    if let Some((duration, value)) = last {
+        let others = durations - value;
+        let value = total.saturating_sub(others).min(0xFF_FFFF);
+        webp[duration..duration + 3].copy_from_slice(&value.to_le_bytes()[..3]);
+    }
+}
+
+/// Re-encodes an already processed thumbnail, e.g. for a service that needs another format.
+///
+/// Animations stay animated when `format` can animate, other formats get the first frame.
+pub fn convert(image: &ProcessedImage, format: ImageFormat) -> Result<ProcessedImage, AppError> {
+    if image.format == format {
+        return Ok(ProcessedImage {
+            data: image.data.clone(),
+            ..*image
+        });
+    }
+
+    let frames = if image.animated && can_animate(format) {
+        // thumbnails are small, the limits for sources are not theirs
+        decode_frames(&image.data, &DecodeLimits::default())?
+    } else {
+        None
+    };
+    let picture = match frames {
+        Some(frames) => Picture::Animation(frames),
+        None => Picture::Still(image::load_from_memory_with_format(
+            &image.data,
+            image.format,
+        )?),
+    };
+
+    // a format that compresses worse may need a lower quality or a smaller size to fit again
+    let options = ImageProcessingOptions {
+        size: Dimensions {
+            width: image.width,
+            height: image.height,
+        },
+        format,
+        encoding: image.encoding,
+        upscale: Upscale::Never,
+        max_bytes: image.max_bytes,
+        ..Default::default()
+    };
+    let (picture, encoding, data) = render(&picture, &options, None)?;
+    let (width, height) = picture.dimensions();
+
+    Ok(ProcessedImage {
+        data,
+        format,
+        encoding,
+        width,
+        height,
+        animated: matches!(picture, Picture::Animation(_)),
+        max_bytes: image.max_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, ImageEncoder, Rgb};
+    use tempfile::TempDir;
+
+    fn create_test_image(width: u32, height: u32) -> (TempDir, String) {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("test_image.png");
+
+        let mut img = ImageBuffer::new(width, height);
+
+        // Fill with a simple pattern
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = Rgb([x as u8, y as u8, 100]);
+        }
+
+        img.save(&file_path).unwrap();
+
+        (temp_dir, file_path.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn test_create_thumbnail_resizes_correctly() {
+        let (temp_dir, file_path) = create_test_image(200, 200);
+
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(100),
+            ..Default::default()
+        };
+
+        let result = create_thumbnail(&file_path, &options).unwrap();
+
+        // Load the resulting image to verify dimensions
+        let img = image::load_from_memory(&result.data).unwrap();
+        assert_eq!(img.dimensions(), (100, 100));
+        assert_eq!((result.width, result.height), (100, 100));
+
+        drop(temp_dir); // Cleanup
+    }
+
+    #[test]
+    fn test_create_thumbnail_converts_format() {
+        let (temp_dir, file_path) = create_test_image(200, 200);
+
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(100),
+            format: ImageFormat::Jpeg,
+            ..Default::default()
+        };
+
+        let result = create_thumbnail(&file_path, &options).unwrap();
+        assert_eq!(result.format, ImageFormat::Jpeg);
+
+        // Verify the data is actually JPEG
+        assert!(image::guess_format(&result.data).unwrap() == ImageFormat::Jpeg);
+
+        drop(temp_dir); // Cleanup
+    }
+
+    #[test]
+    fn test_encode_formats() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8])
+        }));
+
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::WebP,
+            ImageFormat::Jpeg,
+            ImageFormat::Avif,
+            ImageFormat::Gif,
+        ] {
+            let data = encode(&img, format, Encoding::default()).unwrap();
+            assert_eq!(image::guess_format(&data).unwrap(), format);
+        }
+
+        // a lower quality gives a smaller file
+        let quality = |quality| Encoding {
+            quality,
+            ..Encoding::default()
+        };
+        let high = encode(&img, ImageFormat::Jpeg, quality(95)).unwrap();
+        let low = encode(&img, ImageFormat::Jpeg, quality(20)).unwrap();
+        assert!(low.len() < high.len());
+    }
+
+    #[test]
+    fn test_encode_webp_modes() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8])
+        }));
+
+        let lossless = encode(&img, ImageFormat::WebP, Encoding::default()).unwrap();
+        let lossy = Encoding {
+            webp: WebpMode::Lossy,
+            quality: 50,
+            ..Encoding::default()
+        };
+        let lossy = encode(&img, ImageFormat::WebP, lossy).unwrap();
+
+        assert_eq!(image::guess_format(&lossy).unwrap(), ImageFormat::WebP);
+        assert!(lossy.len() < lossless.len());
+        // exact pixels only survive the lossless encoder
+        assert_eq!(
+            image::load_from_memory(&lossless).unwrap().to_rgb8(),
+            img.to_rgb8()
+        );
+        assert_eq!(
+            image::load_from_memory(&lossy).unwrap().dimensions(),
+            (64, 64)
+        );
+    }
+
+    #[test]
+    fn test_encode_png_compression() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(128, 128, |x, y| {
+            Rgb([(x * 2) as u8, (y * 2) as u8, 100])
+        }));
+
+        let fast = encode(&img, ImageFormat::Png, Encoding::default()).unwrap();
+        let best = Encoding {
+            png_compression: PngCompression::Best,
+            png_filter: PngFilter::Paeth,
+            ..Encoding::default()
+        };
+        let best = encode(&img, ImageFormat::Png, best).unwrap();
+
+        assert!(best.len() < fast.len());
+        assert_eq!(
+            image::load_from_memory(&best).unwrap().to_rgb8(),
+            img.to_rgb8()
+        );
+    }
+
+    /// Noise compresses badly, so every setting changes the encoded size
+    fn noisy_image(size: u32) -> Vec<u8> {
+        let mut rng = fastrand::Rng::with_seed(7);
+        let img =
+            ImageBuffer::from_fn(size, size, |_, _| Rgb([rng.u8(..), rng.u8(..), rng.u8(..)]));
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_max_bytes_lowers_quality_first() {
+        let source = noisy_image(256);
+        let unbounded = create_thumbnail_from_memory(
+            &source,
+            &ImageProcessingOptions {
+                format: ImageFormat::Jpeg,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let max_bytes = unbounded.data.len() as u64 * 3 / 4;
+        let result = create_thumbnail_from_memory(
+            &source,
+            &ImageProcessingOptions {
+                format: ImageFormat::Jpeg,
+                max_bytes: Some(max_bytes),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(result.data.len() as u64 <= max_bytes);
+        assert!(result.encoding.quality < 85);
+        assert!(result.encoding.quality >= MIN_QUALITY);
+        assert_eq!((result.width, result.height), (256, 256));
+
+        // the budget is not spent on settings that already fit
+        let roomy = create_thumbnail_from_memory(
+            &source,
+            &ImageProcessingOptions {
+                format: ImageFormat::Jpeg,
+                max_bytes: Some(unbounded.data.len() as u64),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(roomy.encoding.quality, 85);
+    }
+
+    #[test]
+    fn test_max_bytes_shrinks_lossless_formats() {
+        let source = noisy_image(256);
+        let result = create_thumbnail_from_memory(
+            &source,
+            &ImageProcessingOptions {
+                format: ImageFormat::Png,
+                max_bytes: Some(60_000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(result.data.len() <= 60_000);
+        assert!(result.width < 256);
+        assert!(result.width >= MIN_SIDE);
+        assert_eq!(result.width, result.height);
+    }
+
+    #[test]
+    fn test_max_bytes_out_of_reach() {
+        let source = noisy_image(256);
+        let error = create_thumbnail_from_memory(
+            &source,
+            &ImageProcessingOptions {
+                format: ImageFormat::Png,
+                max_bytes: Some(100),
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+
+        assert!(matches!(
+            error,
+            AppError::TooLarge { max_bytes: 100, smallest } if smallest > 100
+        ));
+    }
+
+    #[test]
+    fn test_convert_changes_format() {
+        let (temp_dir, file_path) = create_test_image(200, 200);
+
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(100),
+            format: ImageFormat::WebP,
+            ..Default::default()
+        };
+
+        let webp = create_thumbnail(&file_path, &options).unwrap();
+        let png = convert(&webp, ImageFormat::Png).unwrap();
+
+        assert_eq!(png.format, ImageFormat::Png);
+        assert_eq!(image::guess_format(&png.data).unwrap(), ImageFormat::Png);
+        assert_eq!((png.width, png.height), (100, 100));
+
+        drop(temp_dir); // Cleanup
+    }
+
+    #[test]
+    fn test_convert_keeps_budget() {
+        let source = noisy_image(256);
+        let options = ImageProcessingOptions {
+            format: ImageFormat::Jpeg,
+            max_bytes: Some(40_000),
+            ..Default::default()
+        };
+        let jpeg = create_thumbnail_from_memory(&source, &options).unwrap();
+        assert!(jpeg.data.len() <= 40_000);
+
+        // lossless noise is several times the budget at the same size
+        let png = convert(&jpeg, ImageFormat::Png).unwrap();
+        assert!(png.data.len() <= 40_000);
+        assert!(png.width < jpeg.width);
+        assert_eq!(png.max_bytes, Some(40_000));
+
+        let tight = ProcessedImage {
+            max_bytes: Some(100),
+            ..jpeg
+        };
+        assert!(matches!(
+            convert(&tight, ImageFormat::Png),
+            Err(AppError::TooLarge { max_bytes: 100, .. })
+        ));
+    }
+
+    #[test]
+    fn test_create_thumbnail_invalid_file() {
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(100),
+            ..Default::default()
+        };
+
+        let result = create_thumbnail("nonexistent_file.png", &options);
+        assert!(result.is_err());
+    }
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const GREEN: Rgb<u8> = Rgb([0, 255, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+
+    /// A JPEG with the given quadrant colours as stored, tagged with an EXIF orientation
+    fn oriented_jpeg(orientation: u16, quadrants: [[Rgb<u8>; 2]; 2], portrait: bool) -> Vec<u8> {
+        let (width, height) = if portrait { (20, 40) } else { (40, 20) };
+        let img = ImageBuffer::from_fn(width, height, |x, y| {
+            quadrants[(y * 2 / height) as usize][(x * 2 / width) as usize]
+        });
+
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        // big-endian TIFF header with a single IFD entry: Orientation, SHORT, count 1
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        // APP1 goes right after the start of image marker
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        app1.extend_from_slice(&exif);
+        jpeg.splice(2..2, app1);
+
+        jpeg
+    }
+
+    fn assert_close(actual: Rgba<u8>, expected: Rgb<u8>) {
+        for channel in 0..3 {
+            assert!(
+                actual[channel].abs_diff(expected[channel]) < 40,
+                "{:?} is not {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_exif_orientations() {
+        // how an upright [red green / blue white] image is stored for each orientation
+        let fixtures = [
+            (1, [[RED, GREEN], [BLUE, WHITE]], false),
+            (2, [[GREEN, RED], [WHITE, BLUE]], false),
+            (3, [[WHITE, BLUE], [GREEN, RED]], false),
+            (4, [[BLUE, WHITE], [RED, GREEN]], false),
+            (5, [[RED, BLUE], [GREEN, WHITE]], true),
+            (6, [[GREEN, WHITE], [RED, BLUE]], true),
+            (7, [[WHITE, GREEN], [BLUE, RED]], true),
+            (8, [[BLUE, RED], [WHITE, GREEN]], true),
+        ];
+
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(40),
+            ..Default::default()
+        };
+
+        for (orientation, quadrants, portrait) in fixtures {
+            let jpeg = oriented_jpeg(orientation, quadrants, portrait);
+            let result = create_thumbnail_from_memory(&jpeg, &options).unwrap();
+            assert_eq!((result.width, result.height), (40, 20), "{}", orientation);
+
+            let img = image::load_from_memory(&result.data).unwrap();
+            assert_close(img.get_pixel(10, 5), RED);
+            assert_close(img.get_pixel(30, 5), GREEN);
+            assert_close(img.get_pixel(10, 15), BLUE);
+            assert_close(img.get_pixel(30, 15), WHITE);
+        }
+    }
+
+    #[test]
+    fn test_exif_orientation_is_not_copied() {
+        let jpeg = oriented_jpeg(6, [[GREEN, WHITE], [RED, BLUE]], true);
+        let mut decoder = ImageReader::new(Cursor::new(&jpeg))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert_eq!(decoder.orientation().unwrap(), Orientation::Rotate90);
+
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(40),
+            format: ImageFormat::Jpeg,
+            ..Default::default()
+        };
+        let result = create_thumbnail_from_memory(&jpeg, &options).unwrap();
+
+        // a viewer that honours the tag would otherwise turn the upright thumbnail again
+        let mut decoder = ImageReader::new(Cursor::new(&result.data))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert_eq!(decoder.orientation().unwrap(), Orientation::NoTransforms);
+        assert!(decoder.exif_metadata().unwrap().is_none());
+    }
+
+    /// An ICC v2 display profile with sRGB's primaries but a linear tone curve
+    fn linear_rgb_profile() -> Vec<u8> {
+        let fixed = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+        let xyz = |x: f64, y: f64, z: f64| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for value in [x, y, z] {
+                tag.extend_from_slice(&fixed(value));
+            }
+            tag
+        };
+        // no entries is the identity curve
+        let linear = b"curv\0\0\0\0\0\0\0\0".to_vec();
+
+        let tags: [(&[u8; 4], Vec<u8>); 7] = [
+            (b"wtpt", xyz(0.9642, 1.0, 0.8249)),
+            (b"rXYZ", xyz(0.4361, 0.2225, 0.0139)),
+            (b"gXYZ", xyz(0.3851, 0.7169, 0.0971)),
+            (b"bXYZ", xyz(0.1431, 0.0606, 0.7141)),
+            (b"rTRC", linear.clone()),
+            (b"gTRC", linear.clone()),
+            (b"bTRC", linear),
+        ];
+
+        icc_profile(b"mntr", b"RGB ", b"XYZ ", &tags)
+    }
+
+    /// An ICC v2 profile of the given class, data colour space and connection space
+    fn icc_profile(
+        class: &[u8; 4],
+        space: &[u8; 4],
+        pcs: &[u8; 4],
+        tags: &[(&[u8; 4], Vec<u8>)],
+    ) -> Vec<u8> {
+        let fixed = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let mut offset = 128 + 4 + tags.len() * 12;
+        for (signature, tag) in tags {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&(offset as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            offset += tag.len();
+        }
+
+        let mut header = vec![0; 128];
+        header[0..4].copy_from_slice(&(offset as u32).to_be_bytes());
+        header[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        header[12..16].copy_from_slice(class);
+        header[16..20].copy_from_slice(space);
+        header[20..24].copy_from_slice(pcs);
+        header[36..40].copy_from_slice(b"acsp");
+        header[68..80].copy_from_slice(&[fixed(0.9642), fixed(1.0), fixed(0.8249)].concat());
+
+        [header, table, data].concat()
+    }
+
+    /// A printer profile where paper is white and any full ink is black, through an 8-bit
+    /// A2B0 lookup table into Lab
+    fn cmyk_profile() -> Vec<u8> {
+        let fixed = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+        let mut lut = b"mft1\0\0\0\0".to_vec();
+        lut.extend([4, 3, 2, 0]); // inputs, outputs, grid points, padding
+        for (row, column) in (0..3).flat_map(|row| (0..3).map(move |column| (row, column))) {
+            lut.extend(fixed(if row == column { 1.0 } else { 0.0 }));
+        }
+        for _ in 0..4 {
+            lut.extend(0..=255u8);
+        }
+        // the grid runs over c, m, y, k with k varying fastest
+        for corner in 0..16u32 {
+            let lightness = if corner == 0 { 255 } else { 0 };
+            lut.extend([lightness, 128, 128]);
+        }
+        for _ in 0..3 {
+            lut.extend(0..=255u8);
+        }
+
+        icc_profile(b"prtr", b"CMYK", b"Lab ", &[(b"A2B0", lut)])
+    }
+
+    /// A flat 8x8 baseline CMYK JPEG of `inks` as stored, with an Adobe APP14 segment when
+    /// `adobe` is set, and `icc` embedded
+    fn cmyk_jpeg(inks: [u8; 4], adobe: bool, icc: &[u8]) -> Vec<u8> {
+        let segment = |marker: u8, body: &[u8]| {
+            let mut segment = vec![0xFF, marker];
+            segment.extend(((body.len() + 2) as u16).to_be_bytes());
+            segment.extend(body);
+            segment
+        };
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        if adobe {
+            // version 100, no flags, no colour transform
+            jpeg.extend(segment(0xEE, b"Adobe\0\x64\0\0\0\0\0"));
+        }
+        jpeg.extend(segment(0xE2, &[b"ICC_PROFILE\0\x01\x01", icc].concat()));
+        jpeg.extend(segment(0xDB, &[[0].as_slice(), &[1; 64]].concat()));
+        let mut frame = vec![8, 0, 8, 0, 8, 4];
+        for id in 1..=4 {
+            frame.extend([id, 0x11, 0]);
+        }
+        jpeg.extend(segment(0xC0, &frame));
+        // DC categories 0 to 11 as four bit codes, AC only has end of block
+        let mut dc = vec![0x00, 0, 0, 0, 12];
+        dc.extend([0; 12]);
+        dc.extend(0..12);
+        jpeg.extend(segment(0xC4, &dc));
+        let mut ac = vec![0x10, 1];
+        ac.extend([0; 15]);
+        ac.push(0);
+        jpeg.extend(segment(0xC4, &ac));
+        jpeg.extend(segment(
+            0xDA,
+            &[4, 1, 0x00, 2, 0x00, 3, 0x00, 4, 0x00, 0, 63, 0],
+        ));
+
+        // a flat block only has a DC coefficient, eight times the level-shifted sample
+        let mut bits = Vec::new();
+        for ink in inks {
+            let dc = 8 * (ink as i32 - 128);
+            let category = 32 - dc.unsigned_abs().leading_zeros();
+            let value = if dc < 0 { dc - 1 } else { dc } as u32;
+            bits.extend((0..4).rev().map(|bit| category >> bit & 1));
+            bits.extend((0..category).rev().map(|bit| value >> bit & 1));
+            bits.push(0);
+        }
+        while bits.len() % 8 != 0 {
+            bits.push(1);
+        }
+        for byte in bits.chunks(8) {
+            let byte = byte.iter().fold(0u8, |byte, bit| byte << 1 | *bit as u8);
+            jpeg.push(byte);
+            if byte == 0xFF {
+                jpeg.push(0);
+            }
+        }
+
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn test_cmyk_jpeg_is_converted_to_srgb() {
+        let icc = cmyk_profile();
+        let colour = |inks, adobe| {
+            let img = decode(&cmyk_jpeg(inks, adobe, &icc), &DecodeLimits::default()).unwrap();
+            img.to_rgb8().get_pixel(4, 4).0
+        };
+
+        // plain CMYK stores ink amounts, Adobe stores them inverted
+        for (paper, black, adobe) in [
+            ([0; 4], [0, 0, 0, 255], false),
+            ([255; 4], [255, 255, 255, 0], true),
+        ] {
+            let white = colour(paper, adobe);
+            assert!(white.iter().all(|channel| *channel > 245), "{:?}", white);
+            let ink = colour(black, adobe);
+            assert!(ink.iter().all(|channel| *channel < 10), "{:?}", ink);
+        }
+    }
+
+    /// A flat grey PNG carrying `icc`
+    fn tagged_png(icc: Vec<u8>) -> Vec<u8> {
+        let img = ImageBuffer::from_pixel(8, 8, Rgb([128u8, 128, 128]));
+        let mut png = Vec::new();
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+        encoder.set_icc_profile(icc).unwrap();
+        img.write_with_encoder(encoder).unwrap();
+        png
+    }
+
+    #[test]
+    fn test_icc_profile_is_converted_to_srgb() {
+        let png = tagged_png(linear_rgb_profile());
+        let mut decoder = ImageReader::new(Cursor::new(&png))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        assert!(decoder.icc_profile().unwrap().is_some());
+
+        // half intensity in linear light is about 188 once sRGB encoded
+        let img = decode(&png, &DecodeLimits::default()).unwrap();
+        let Rgba([r, g, b, _]) = img.get_pixel(4, 4);
+        for channel in [r, g, b] {
+            assert!(channel.abs_diff(188) <= 2, "{}", channel);
+        }
+    }
+
+    #[test]
+    fn test_unreadable_icc_profile_is_ignored() {
+        let img = decode(
+            &tagged_png(b"not a profile".to_vec()),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(img.get_pixel(4, 4), Rgba([128, 128, 128, 255]));
+    }
+
+    #[test]
+    fn test_output_has_no_metadata() {
+        let png = tagged_png(linear_rgb_profile());
+
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP] {
+            let options = ImageProcessingOptions {
+                size: Dimensions::square(8),
+                format,
+                ..Default::default()
+            };
+            let result = create_thumbnail_from_memory(&png, &options).unwrap();
+
+            let mut decoder = ImageReader::new(Cursor::new(&result.data))
+                .with_guessed_format()
+                .unwrap()
+                .into_decoder()
+                .unwrap();
+            assert!(decoder.icc_profile().unwrap().is_none(), "{:?}", format);
+            assert!(decoder.exif_metadata().unwrap().is_none(), "{:?}", format);
+        }
+    }
+
+    fn wide_image() -> DynamicImage {
+        // red on the left third, green in the middle, blue on the right
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(300, 100, |x, _| match x / 100 {
+            0 => Rgb([255, 0, 0]),
+            1 => Rgb([0, 255, 0]),
+            _ => Rgb([0, 0, 255]),
+        }))
+    }
+
+    #[test]
+    fn test_resize_modes() {
+        let img = wide_image();
+        let background = Background::default();
+
+        let fit = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Fit,
+            background,
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(fit.dimensions(), (60, 20));
+
+        // fill keeps the centre, the green third
+        let fill = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Fill,
+            background,
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(fill.dimensions(), (60, 60));
+        assert_eq!(fill.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+        assert_eq!(fill.get_pixel(59, 59), Rgba([0, 255, 0, 255]));
+
+        let stretch = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Stretch,
+            background,
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(stretch.dimensions(), (60, 60));
+        assert_eq!(stretch.get_pixel(0, 30), Rgba([255, 0, 0, 255]));
+        assert_eq!(stretch.get_pixel(59, 30), Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn test_resize_pad() {
+        let img = wide_image();
+
+        let pad = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Pad,
+            Background::Color(Rgba([255, 255, 255, 255])),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(pad.dimensions(), (60, 60));
+        assert!(!pad.color().has_alpha());
+        // letterboxed: white bars above and below, the image in the middle
+        assert_eq!(pad.get_pixel(30, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(pad.get_pixel(30, 59), Rgba([255, 255, 255, 255]));
+        assert_eq!(pad.get_pixel(2, 30), Rgba([255, 0, 0, 255]));
+
+        let transparent = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Pad,
+            Background::Color(Rgba([0, 0, 0, 0])),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert!(transparent.color().has_alpha());
+        assert_eq!(transparent.get_pixel(30, 0), Rgba([0, 0, 0, 0]));
+
+        // the blurred backdrop is made from the image, not a flat colour
+        let blur = resize(
+            &img,
+            Dimensions::square(60),
+            ResizeMode::Pad,
+            Background::Blur,
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(blur.dimensions(), (60, 60));
+        let top = blur.get_pixel(30, 2);
+        assert!(top[1] > top[0] && top[1] > top[2]);
+    }
+
+    #[test]
+    fn test_background_parsing() {
+        assert_eq!("blur".parse::<Background>().unwrap(), Background::Blur);
+        assert_eq!(
+            "#ff8000".parse::<Background>().unwrap(),
+            Background::Color(Rgba([255, 128, 0, 255]))
+        );
+        assert_eq!(
+            "00000080".parse::<Background>().unwrap(),
+            Background::Color(Rgba([0, 0, 0, 128]))
+        );
+        assert_eq!(
+            Background::Color(Rgba([255, 128, 0, 255])).to_string(),
+            "#ff8000"
+        );
+        assert_eq!(
+            Background::Color(Rgba([0, 0, 0, 128])).to_string(),
+            "#00000080"
+        );
+
+        for invalid in ["", "#fff", "#gggggg", "red", "#ff8000ff00"] {
+            assert!(invalid.parse::<Background>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_alpha_parsing() {
+        assert_eq!("keep".parse::<Alpha>().unwrap(), Alpha::Keep);
+        assert_eq!("AUTO".parse::<Alpha>().unwrap(), Alpha::Auto);
+        assert_eq!(
+            "#ff8000".parse::<Alpha>().unwrap(),
+            Alpha::Flatten(Rgb([255, 128, 0]))
+        );
+        assert_eq!(Alpha::Flatten(Rgb([255, 128, 0])).to_string(), "#ff8000");
+
+        for invalid in ["", "blur", "#ff800080", "transparent"] {
+            assert!(invalid.parse::<Alpha>().is_err());
+        }
+    }
+
+    /// A 40x20 PNG, opaque `color` on the left half and fully transparent on the right
+    fn half_transparent_png(color: [u8; 3]) -> Vec<u8> {
+        let [r, g, b] = color;
+        let img = RgbaImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgba([r, g, b, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_alpha_policies() {
+        let thumbnail = |source: &[u8], format, alpha| {
+            let options = ImageProcessingOptions {
+                size: Dimensions::square(40),
+                format,
+                alpha,
+                ..Default::default()
+            };
+            let result = create_thumbnail_from_memory(source, &options).unwrap();
+            image::load_from_memory(&result.data).unwrap()
+        };
+        let black = half_transparent_png([0, 0, 0]);
+
+        let kept = thumbnail(&black, ImageFormat::Png, Alpha::Keep);
+        assert_eq!(kept.get_pixel(30, 10)[3], 0);
+
+        let flat = thumbnail(&black, ImageFormat::Png, Alpha::Flatten(Rgb([255, 0, 0])));
+        assert!(!flat.color().has_alpha());
+        assert_eq!(flat.get_pixel(30, 10), Rgba([255, 0, 0, 255]));
+        assert_eq!(flat.get_pixel(5, 10), Rgba([0, 0, 0, 255]));
+
+        // dark artwork gets a light matte and light artwork a dark one
+        let auto = thumbnail(&black, ImageFormat::Png, Alpha::Auto);
+        assert_eq!(auto.get_pixel(30, 10), Rgba([191, 191, 191, 255]));
+        let white = half_transparent_png([255, 255, 255]);
+        let auto = thumbnail(&white, ImageFormat::Png, Alpha::Auto);
+        assert_eq!(auto.get_pixel(30, 10), Rgba([63, 63, 63, 255]));
+
+        // JPEG cannot keep transparency and flattens onto white instead of failing
+        let jpeg = thumbnail(&black, ImageFormat::Jpeg, Alpha::Keep);
+        assert!(jpeg.get_pixel(30, 10)[0] > 245);
+        assert!(jpeg.get_pixel(5, 10)[0] < 10);
+    }
+
+    #[test]
+    fn test_resize_in_linear_light() {
+        // a fine black and white checkerboard is half the light of white
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        }));
+        let size = Dimensions::square(8);
+        let resampling = Resampling {
+            filter: FilterType::Triangle,
+            linear: false,
+        };
+
+        let gamma = resize(
+            &img,
+            size,
+            ResizeMode::Fit,
+            Background::default(),
+            resampling,
+            Upscale::Allow,
+        );
+        let linear = resize(
+            &img,
+            size,
+            ResizeMode::Fit,
+            Background::default(),
+            Resampling {
+                linear: true,
+                ..resampling
+            },
+            Upscale::Allow,
+        );
+
+        // averaging encoded values comes out too dark
+        assert!(gamma.get_pixel(4, 4)[0].abs_diff(128) <= 2);
+        assert!(linear.get_pixel(4, 4)[0].abs_diff(188) <= 2);
+        assert_eq!(linear.color(), image::ColorType::Rgb8);
+    }
+
+    #[test]
+    fn test_resize_filters() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(90, 90, |x, y| {
+            Rgb([(x * 2) as u8, (y * 2) as u8, ((x * y) % 256) as u8])
+        }));
+        let with = |filter| {
+            resize(
+                &img,
+                Dimensions::square(32),
+                ResizeMode::Fit,
+                Background::default(),
+                Resampling {
+                    filter,
+                    linear: false,
+                },
+                Upscale::Allow,
+            )
+        };
+
+        let nearest = with(FilterType::Nearest);
+        let lanczos = with(FilterType::Lanczos3);
+        assert_eq!(nearest.dimensions(), (32, 32));
+        assert_eq!(lanczos.dimensions(), (32, 32));
+        assert_ne!(nearest.to_rgb8(), lanczos.to_rgb8());
+    }
+
+    #[test]
+    fn test_resize_to_banner() {
+        let img = wide_image();
+        let banner = Dimensions {
+            width: 120,
+            height: 20,
+        };
+
+        // fit stays inside the box, the others fill it
+        assert_eq!(
+            resize(
+                &img,
+                banner,
+                ResizeMode::Fit,
+                Background::default(),
+                Resampling::default(),
+                Upscale::Allow
+            )
+            .dimensions(),
+            (60, 20)
+        );
+        for mode in [ResizeMode::Fill, ResizeMode::Pad, ResizeMode::Stretch] {
+            assert_eq!(
+                resize(
+                    &img,
+                    banner,
+                    mode,
+                    Background::default(),
+                    Resampling::default(),
+                    Upscale::Allow
+                )
+                .dimensions(),
+                (120, 20)
+            );
+        }
+
+        // a 6:1 crop of a 3:1 image keeps the middle rows, all three colours stay
+        let fill = resize(
+            &img,
+            banner,
+            ResizeMode::Fill,
+            Background::default(),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(fill.get_pixel(0, 10), Rgba([255, 0, 0, 255]));
+        assert_eq!(fill.get_pixel(119, 10), Rgba([0, 0, 255, 255]));
+
+        // a portrait box crops the sides
+        let portrait = Dimensions {
+            width: 20,
+            height: 40,
+        };
+        let fill = resize(
+            &img,
+            portrait,
+            ResizeMode::Fill,
+            Background::default(),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(fill.dimensions(), (20, 40));
+        assert_eq!(fill.get_pixel(10, 20), Rgba([0, 255, 0, 255]));
+
+        let pad = resize(
+            &img,
+            portrait,
+            ResizeMode::Pad,
+            Background::default(),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(pad.dimensions(), (20, 40));
+    }
+
+    #[test]
+    fn test_upscale_parsing() {
+        assert_eq!("never".parse::<Upscale>().unwrap(), Upscale::Never);
+        assert_eq!("allow".parse::<Upscale>().unwrap(), Upscale::Allow);
+        assert_eq!(
+            "allow-up-to-2x".parse::<Upscale>().unwrap(),
+            Upscale::UpTo(2.0)
+        );
+        assert_eq!(
+            "allow-up-to-1.5x".parse::<Upscale>().unwrap(),
+            Upscale::UpTo(1.5)
+        );
+        assert_eq!(Upscale::UpTo(2.0).to_string(), "allow-up-to-2x");
+
+        for invalid in [
+            "",
+            "always",
+            "allow-up-to-x",
+            "allow-up-to-0.5x",
+            "allow-up-to-2",
+        ] {
+            assert!(invalid.parse::<Upscale>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_resize_upscale_policy() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(40, 20, Rgb([255, 0, 0])));
+        let size = Dimensions::square(100);
+        let resized = |mode, upscale| {
+            resize(
+                &img,
+                size,
+                mode,
+                Background::Color(Rgba([255, 255, 255, 255])),
+                Resampling::default(),
+                upscale,
+            )
+        };
+
+        assert_eq!(
+            resized(ResizeMode::Fit, Upscale::Allow).dimensions(),
+            (100, 50)
+        );
+        assert_eq!(
+            resized(ResizeMode::Fit, Upscale::Never).dimensions(),
+            (40, 20)
+        );
+        assert_eq!(
+            resized(ResizeMode::Fit, Upscale::UpTo(2.0)).dimensions(),
+            (80, 40)
+        );
+
+        // the square crop is 20x20, which is as far as fill may go
+        assert_eq!(
+            resized(ResizeMode::Fill, Upscale::Never).dimensions(),
+            (20, 20)
+        );
+        assert_eq!(
+            resized(ResizeMode::Stretch, Upscale::Never).dimensions(),
+            (40, 20)
+        );
+
+        // pad keeps the box and centres the image at its own size
+        let pad = resized(ResizeMode::Pad, Upscale::Never);
+        assert_eq!(pad.dimensions(), (100, 100));
+        assert_eq!(pad.get_pixel(50, 50), Rgba([255, 0, 0, 255]));
+        assert_eq!(pad.get_pixel(20, 50), Rgba([255, 255, 255, 255]));
+        assert_eq!(pad.get_pixel(50, 35), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn test_min_source_size() {
+        let (temp_dir, file_path) = create_test_image(200, 100);
+
+        let mut options = ImageProcessingOptions {
+            size: Dimensions::square(100),
+            min_source_size: Some(Dimensions::square(150)),
+            ..Default::default()
+        };
+
+        match create_thumbnail(&file_path, &options) {
+            Err(AppError::SourceTooSmall { size, min }) => {
+                assert_eq!(size.to_string(), "200x100");
+                assert_eq!(min, Dimensions::square(150));
+            }
+            result => panic!("unexpected result {:?}", result.map(|r| r.width)),
+        }
+
+        options.min_source_size = Some(Dimensions {
+            width: 200,
+            height: 100,
+        });
+        assert!(create_thumbnail(&file_path, &options).is_ok());
+
+        drop(temp_dir);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let (temp_dir, file_path) = create_test_image(200, 100);
+        let png = std::fs::read(&file_path).unwrap();
+        let limited = |limits| ImageProcessingOptions {
+            limits,
+            ..Default::default()
+        };
+        let error = |result: Result<ProcessedImage, AppError>| match result {
+            Err(AppError::DecodeLimit(reason)) => reason,
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("the limits were not applied"),
+        };
+
+        let options = limited(DecodeLimits {
+            max_file_size: 100,
+            ..DecodeLimits::default()
+        });
+        assert!(error(create_thumbnail(&file_path, &options)).starts_with("the file is"));
+        assert!(error(create_thumbnail_from_memory(&png, &options)).starts_with("the file is"));
+
+        let options = limited(DecodeLimits {
+            max_dimensions: Dimensions::square(150),
+            ..DecodeLimits::default()
+        });
+        assert_eq!(
+            error(create_thumbnail_from_memory(&png, &options)),
+            "the image is 200x100, at most 150x150 is allowed"
+        );
+
+        let options = limited(DecodeLimits {
+            max_alloc: 1000,
+            ..DecodeLimits::default()
+        });
+        assert_eq!(
+            error(create_thumbnail_from_memory(&png, &options)),
+            "decoding needs 60000 bytes, at most 1000 are allowed"
+        );
+
+        // one 40x20 frame fits, the animation as a whole does not
+        let options = ImageProcessingOptions {
+            limits: DecodeLimits {
+                max_alloc: 5000,
+                ..DecodeLimits::default()
+            },
+            format: ImageFormat::Gif,
+            animation: Animation::Animate,
+            ..Default::default()
+        };
+        assert!(
+            error(create_thumbnail_from_memory(&animated_gif(), &options))
+                .starts_with("decoding needs")
+        );
+
+        drop(temp_dir);
+    }
+
+    /// A 40x20 GIF of a red, a green and a blue frame, shown for 100, 200 and 300 ms
+    fn animated_gif() -> Vec<u8> {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for (i, color) in [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]
+                .into_iter()
+                .enumerate()
+            {
+                let delay = image::Delay::from_numer_denom_ms(100 * (i as u32 + 1), 1);
+                let frame = RgbaImage::from_pixel(40, 20, Rgba(color));
+                encoder
+                    .encode_frame(Frame::from_parts(frame, 0, 0, delay))
+                    .unwrap();
+            }
+        }
+        gif
+    }
+
+    fn delays(frames: &[Frame]) -> Vec<u32> {
+        frames
+            .iter()
+            .map(|frame| {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                numerator / denominator
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_animation_frame_choice() {
+        let gif = animated_gif();
+        let still = |frame| {
+            let options = ImageProcessingOptions {
+                size: Dimensions::square(20),
+                format: ImageFormat::Png,
+                animation: Animation::Frame(frame),
+                ..Default::default()
+            };
+            let result = create_thumbnail_from_memory(&gif, &options).unwrap();
+            assert!(!result.animated);
+            image::load_from_memory(&result.data).unwrap().to_rgb8()
+        };
+
+        assert_eq!(still(1).get_pixel(10, 5), &Rgb([255, 0, 0]));
+        assert_eq!(still(2).get_pixel(10, 5), &Rgb([0, 255, 0]));
+        // past the end is the last frame
+        assert_eq!(still(9).get_pixel(10, 5), &Rgb([0, 0, 255]));
+
+        // still sources only have a first frame
+        let (temp_dir, file_path) = create_test_image(40, 40);
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(20),
+            format: ImageFormat::Png,
+            animation: Animation::Frame(2),
+            ..Default::default()
+        };
+        assert!(create_thumbnail(&file_path, &options).is_ok());
+        let png = std::fs::read(&file_path).unwrap();
+        assert!(decode_frames(&png, &DecodeLimits::default())
+            .unwrap()
+            .is_none());
+        drop(temp_dir);
+    }
+
+    #[test]
+    fn test_animate_keeps_frames_and_delays() {
+        let gif = animated_gif();
+
+        for format in [ImageFormat::Gif, ImageFormat::WebP] {
+            let options = ImageProcessingOptions {
+                size: Dimensions::square(20),
+                format,
+                animation: Animation::Animate,
+                ..Default::default()
+            };
+            let result = create_thumbnail_from_memory(&gif, &options).unwrap();
+            assert!(result.animated);
+            assert_eq!((result.width, result.height), (20, 10));
+
+            let frames = decode_frames(&result.data, &DecodeLimits::default())
+                .unwrap()
+                .unwrap();
+            assert_eq!(frames.len(), 3);
+            assert_eq!(frames[0].buffer().dimensions(), (20, 10));
+            assert_eq!(delays(&frames), vec![100, 200, 300]);
+        }
+
+        // formats that cannot animate get the first frame
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(20),
+            format: ImageFormat::Png,
+            animation: Animation::Animate,
+            ..Default::default()
+        };
+        let result = create_thumbnail_from_memory(&gif, &options).unwrap();
+        assert!(!result.animated);
+        let png = image::load_from_memory(&result.data).unwrap().to_rgb8();
+        assert_eq!(png.get_pixel(10, 5), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn test_convert_animation() {
+        let options = ImageProcessingOptions {
+            size: Dimensions::square(20),
+            format: ImageFormat::Gif,
+            animation: Animation::Animate,
+            ..Default::default()
+        };
+        let gif = create_thumbnail_from_memory(&animated_gif(), &options).unwrap();
+
+        let webp = convert(&gif, ImageFormat::WebP).unwrap();
+        assert!(webp.animated);
+        let frames = decode_frames(&webp.data, &DecodeLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(delays(&frames), vec![100, 200, 300]);
+
+        let png = convert(&gif, ImageFormat::Png).unwrap();
+        assert!(!png.animated);
+        assert!(decode_frames(&png.data, &DecodeLimits::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_dimensions_parsing() {
+        assert_eq!(
+            "256".parse::<Dimensions>().unwrap(),
+            Dimensions::square(256)
+        );
+        assert_eq!(
+            "512x256".parse::<Dimensions>().unwrap(),
+            Dimensions {
+                width: 512,
+                height: 256
+            }
+        );
+        assert_eq!(
+            "512X256".parse::<Dimensions>().unwrap().to_string(),
+            "512x256"
+        );
+
+        for invalid in ["", "x", "512x", "wide", "-1", "512x256x2"] {
+            assert!(invalid.parse::<Dimensions>().is_err());
+        }
+    }
+
+    /// Flat grey with a checkerboard where `salient` says, the only detail in the image
+    fn salient_image(width: u32, height: u32, salient: (u32, u32, u32, u32)) -> DynamicImage {
+        let (sx, sy, sw, sh) = salient;
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            let inside = (sx..sx + sw).contains(&x) && (sy..sy + sh).contains(&y);
+            if !inside {
+                Rgb([128, 128, 128])
+            } else if (x / 4 + y / 4) % 2 == 0 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        }))
+    }
+
+    fn contains(window: (u32, u32, u32, u32), region: (u32, u32, u32, u32)) -> bool {
+        let (x, y, w, h) = window;
+        let (rx, ry, rw, rh) = region;
+        rx >= x && ry >= y && rx + rw <= x + w && ry + rh <= y + h
+    }
+
+    #[test]
+    fn test_smart_crop_finds_salient_region() {
+        let square = Dimensions::square(64);
+
+        // a logo near the left edge of a wide image, a centre crop would cut it off
+        let region = (20, 100, 120, 80);
+        let img = salient_image(900, 300, region);
+        let window = smart_crop_window(&img, square);
+        assert_eq!((window.2, window.3), (300, 300));
+        assert!(contains(window, region), "{:?}", window);
+        assert!(!contains(centre_crop_window(&img, square), region));
+
+        // near the bottom of a tall image
+        let region = (50, 700, 150, 120);
+        let img = salient_image(300, 900, region);
+        let window = smart_crop_window(&img, square);
+        assert!(contains(window, region), "{:?}", window);
+
+        // near the right edge, with a banner box
+        let region = (1400, 20, 150, 60);
+        let img = salient_image(1600, 400, region);
+        let banner = Dimensions {
+            width: 300,
+            height: 100,
+        };
+        let window = smart_crop_window(&img, banner);
+        assert_eq!((window.2, window.3), (1200, 400));
+        assert!(contains(window, region), "{:?}", window);
+    }
+
+    #[test]
+    fn test_smart_crop_is_centred_without_detail() {
+        let img = salient_image(900, 300, (0, 0, 0, 0));
+        let square = Dimensions::square(64);
+
+        assert_eq!(
+            smart_crop_window(&img, square),
+            centre_crop_window(&img, square)
+        );
+
+        // nothing to choose when the aspect ratio already matches
+        let img = salient_image(300, 300, (10, 10, 50, 50));
+        assert_eq!(smart_crop_window(&img, square), (0, 0, 300, 300));
+
+        let smart = resize(
+            &img,
+            square,
+            ResizeMode::Smart,
+            Background::default(),
+            Resampling::default(),
+            Upscale::Allow,
+        );
+        assert_eq!(smart.dimensions(), (64, 64));
     }
 }
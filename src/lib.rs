@@ -1,6 +1,10 @@
+pub mod artwork;
+pub mod cache;
 pub mod cli;
 pub mod config;
+pub mod config_file;
 pub mod errors;
 pub mod image_processor;
 pub mod models;
+pub mod output;
 pub mod uploaders;
@@ -1,27 +1,35 @@
-mod cli;
-mod config;
-mod errors;
-mod image_processor;
-mod models;
-mod uploaders;
-
 use clap::Parser;
-use cli::read_filepath;
-use uploaders::upload;
 
-use crate::cli::{Cli, OutputFormat};
-use crate::config::Config;
-use crate::errors::AppError;
-use crate::image_processor::{create_thumbnail, ImageProcessingOptions};
+use rich_thumbnail_uploader::artwork::read_source;
+use rich_thumbnail_uploader::cache::{is_link_alive, UploadCache};
+use rich_thumbnail_uploader::cli::{read_filepath, Cli};
+use rich_thumbnail_uploader::config::Config;
+use rich_thumbnail_uploader::errors::AppError;
+use rich_thumbnail_uploader::image_processor::create_thumbnail_from_memory;
+use rich_thumbnail_uploader::output::{render, UploadRecord};
+use rich_thumbnail_uploader::uploaders::{blocking, http_client, upload_with_fallback};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(app());
+
+    // a thumbnail still being made after the deadline is abandoned instead of waited for
+    runtime.shutdown_background();
+    result
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn app() -> Result<(), Box<dyn std::error::Error>> {
     // Parse command line arguments
     let args = Cli::parse();
 
     // Initialize configuration
     let config = Config::new(&args)?;
 
+    if args.show_config {
+        println!("{}", config.describe());
+        return Ok(());
+    }
+
     let input_file = read_filepath()?;
 
     // Validate file exists
@@ -29,28 +37,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Err(AppError::FileNotFound(input_file.to_string_lossy().to_string()).into());
     }
 
-    // Create image processing options
-    let options = ImageProcessingOptions {
-        size: config.image_dimensions.0,
-        format: config.image_format.to_image_format(),
+    // foobar waits for the command, so the whole run is bounded, decoding and encoding run on
+    // blocking threads for the deadline to fire while they are busy
+    let record = config.timeouts.bound(run(&config, &input_file)).await?;
+
+    let record = UploadRecord {
+        profile: config.profile.clone(),
+        ..record
     };
 
-    // Process the image
-    let thumbnail = create_thumbnail(&input_file.to_string_lossy(), &options)?;
-
-    // Upload the image
-    let upload_result = upload(
-        config.service,
-        thumbnail,
-        config.client_id.unwrap_or_default(),
-        config.user_agent.to_string(),
-    )
+    // Output the result
+    println!(
+        "{}",
+        render(config.output, config.template.as_deref(), &record)?
+    );
+
+    Ok(())
+}
+
+async fn run(config: &Config, input_file: &std::path::Path) -> Result<UploadRecord, AppError> {
+    let client = http_client(config.user_agent, &config.timeouts)?;
+
+    // Load the artwork, either the image itself or the art embedded in an audio file
+    let source = blocking({
+        let (input_file, folder_art, limits) = (
+            input_file.to_path_buf(),
+            config.folder_art.clone(),
+            config.limits,
+        );
+        move || read_source(&input_file, &folder_art, &limits)
+    })
     .await?;
 
-    // Output the result
-    match args.output {
-        OutputFormat::Url => println!("{}", upload_result),
+    // Reuse a previous upload of the same art with the same settings
+    let mut cache = config.cache_path.as_deref().map(UploadCache::open);
+    let cache_key = cache.as_ref().map(|_| UploadCache::key(&source, config));
+
+    if let (Some(cache), Some(key)) = (&mut cache, &cache_key) {
+        if let Some(entry) = cache.get(key, |service| config.ttl_for(service)) {
+            if !config.cache_revalidate || is_link_alive(&entry.record.url, &client).await {
+                return Ok(UploadRecord {
+                    cached: true,
+                    ..entry.record.clone()
+                });
+            }
+
+            // the link is gone, upload again
+            cache.remove(key);
+        }
     }
 
-    Ok(())
+    // Process the image
+    let options = config.processing_options();
+    let thumbnail = blocking(move || create_thumbnail_from_memory(&source, &options)).await?;
+
+    // Upload the image, falling back to the next service on failure
+    let (uploaded, upload_result) =
+        upload_with_fallback(&config.targets(), thumbnail, &client, &config.retry).await?;
+    let record = UploadRecord::new(&uploaded, upload_result);
+
+    // Remember the upload, a failing cache should not fail the upload itself
+    if let (Some(cache), Some(key)) = (&mut cache, cache_key) {
+        cache.insert(key, record.clone());
+        if let Err(e) = cache.save() {
+            eprintln!("Warning: {}", e);
+        }
+    }
+
+    Ok(record)
 }
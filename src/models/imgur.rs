@@ -9,4 +9,5 @@ pub struct ImgurResponse {
 #[derive(Deserialize)]
 pub struct ImgurData {
     pub link: String,
+    pub deletehash: Option<String>,
 }
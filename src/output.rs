@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cli::OutputFormat;
+use crate::errors::AppError;
+use crate::image_processor::ProcessedImage;
+use crate::uploaders::{UploadResult, UploadServiceIdentifier};
+
+/// Everything known about an uploaded thumbnail, printed according to `OutputFormat`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UploadRecord {
+    pub url: String,
+    pub service: UploadServiceIdentifier,
+    pub deletehash: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: usize,
+    pub format: String,
+    /// Encoder quality, for lossy formats only
+    pub quality: Option<u8>,
+    /// Hash of the uploaded thumbnail bytes
+    pub sha256: String,
+    /// Set when the record was served from the upload cache. Belongs to the run, so it is kept
+    /// out of the cache file and only added to the JSON output by `render`
+    #[serde(skip)]
+    pub cached: bool,
+    /// Config file profile the run used, like `cached` it is not stored with the record
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// JSON output: the stored record plus the fields that only describe the run
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    #[serde(flatten)]
+    record: &'a UploadRecord,
+    cached: bool,
+    profile: Option<&'a str>,
+}
+
+impl UploadRecord {
+    /// Combines the thumbnail that was sent with what the service returned
+    pub fn new(image: &ProcessedImage, result: UploadResult) -> Self {
+        UploadRecord {
+            url: result.url,
+            service: result.service,
+            deletehash: result.deletehash,
+            width: image.width,
+            height: image.height,
+            bytes: image.data.len(),
+            format: image
+                .format
+                .extensions_str()
+                .first()
+                .map(|ext| ext.to_string())
+                .unwrap_or_default(),
+            quality: image
+                .encoding
+                .is_lossy(image.format)
+                .then_some(image.encoding.quality),
+            sha256: format!("{:x}", Sha256::digest(&image.data)),
+            cached: false,
+            profile: None,
+        }
+    }
+
+    fn placeholder(&self, name: &str) -> String {
+        match name {
+            "url" => self.url.clone(),
+            "service" => self.service.to_string(),
+            "deletehash" => self.deletehash.clone().unwrap_or_default(),
+            "width" => self.width.to_string(),
+            "height" => self.height.to_string(),
+            "bytes" => self.bytes.to_string(),
+            "format" => self.format.clone(),
+            "quality" => self.quality.map(|q| q.to_string()).unwrap_or_default(),
+            "sha256" => self.sha256.clone(),
+            "cached" => self.cached.to_string(),
+            "profile" => self.profile.clone().unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Names that can be used as `{name}` in an output template
+pub static PLACEHOLDERS: [&str; 11] = [
+    "url",
+    "service",
+    "deletehash",
+    "width",
+    "height",
+    "bytes",
+    "format",
+    "quality",
+    "sha256",
+    "cached",
+    "profile",
+];
+
+enum Segment<'a> {
+    Literal(String),
+    Placeholder(&'a str),
+}
+
+/// Splits a template into literal text and placeholders, `{{` and `}}` are literal braces
+fn parse_template(template: &str) -> Result<Vec<Segment<'_>>, AppError> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+
+    while let Some(i) = rest.find(['{', '}']) {
+        literal.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+
+        // doubled braces are escapes
+        if let Some(after) = rest.strip_prefix(brace) {
+            literal.push_str(brace);
+            rest = after;
+            continue;
+        }
+
+        if brace == "}" {
+            return Err(AppError::Config(
+                "unmatched '}' in output template".to_string(),
+            ));
+        }
+
+        let Some(end) = rest.find('}') else {
+            return Err(AppError::Config(
+                "unclosed '{' in output template".to_string(),
+            ));
+        };
+
+        let name = &rest[..end];
+        if !PLACEHOLDERS.contains(&name) {
+            return Err(AppError::Config(format!(
+                "unknown placeholder {{{}}} in output template, expected one of: {}",
+                name,
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+        }
+        segments.push(Segment::Placeholder(name));
+        rest = &rest[end + 1..];
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    Ok(segments)
+}
+
+pub fn validate_template(template: &str) -> Result<(), AppError> {
+    parse_template(template).map(|_| ())
+}
+
+pub fn render(
+    output: OutputFormat,
+    template: Option<&str>,
+    record: &UploadRecord,
+) -> Result<String, AppError> {
+    match output {
+        OutputFormat::Url => Ok(record.url.clone()),
+        OutputFormat::Json => {
+            let output = JsonOutput {
+                record,
+                cached: record.cached,
+                profile: record.profile.as_deref(),
+            };
+            serde_json::to_string(&output).map_err(|e| AppError::Output(e.to_string()))
+        }
+        OutputFormat::Template => {
+            let template = template.ok_or_else(|| {
+                AppError::Config("template output requires --template".to_string())
+            })?;
+
+            Ok(parse_template(template)?
+                .into_iter()
+                .map(|segment| match segment {
+                    Segment::Literal(text) => text,
+                    Segment::Placeholder(name) => record.placeholder(name),
+                })
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor::Encoding;
+    use image::ImageFormat;
+
+    fn record() -> UploadRecord {
+        let image = ProcessedImage {
+            data: vec![0; 1234],
+            format: ImageFormat::Png,
+            encoding: Encoding::default(),
+            width: 256,
+            height: 200,
+            animated: false,
+            max_bytes: None,
+        };
+
+        UploadRecord::new(
+            &image,
+            UploadResult {
+                url: "https://i.imgur.com/abc.png".to_string(),
+                service: UploadServiceIdentifier::Imgur,
+                deletehash: Some("xyz".to_string()),
+            },
+        )
+    }
+
+    #[test]
+    fn test_render_url() {
+        assert_eq!(
+            render(OutputFormat::Url, None, &record()).unwrap(),
+            "https://i.imgur.com/abc.png"
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let json = render(OutputFormat::Json, None, &record()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["url"], "https://i.imgur.com/abc.png");
+        assert_eq!(value["service"], "imgur");
+        assert_eq!(value["deletehash"], "xyz");
+        assert_eq!(value["width"], 256);
+        assert_eq!(value["height"], 200);
+        assert_eq!(value["bytes"], 1234);
+        assert_eq!(value["format"], "png");
+        assert_eq!(value["quality"], serde_json::Value::Null);
+        assert_eq!(value["sha256"], format!("{:x}", Sha256::digest([0; 1234])));
+        assert_eq!(value["cached"], false);
+        assert_eq!(value["profile"], serde_json::Value::Null);
+
+        let record = UploadRecord {
+            cached: true,
+            profile: Some("discord".to_string()),
+            ..record()
+        };
+        let json = render(OutputFormat::Json, None, &record).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["cached"], true);
+        assert_eq!(value["profile"], "discord");
+    }
+
+    #[test]
+    fn test_render_template() {
+        let template =
+            "[img]{url}[/img] {width}x{height} {format} {bytes}B {deletehash} {{{service}}}";
+        assert_eq!(
+            render(OutputFormat::Template, Some(template), &record()).unwrap(),
+            "[img]https://i.imgur.com/abc.png[/img] 256x200 png 1234B xyz {imgur}"
+        );
+
+        let jpeg = UploadRecord {
+            format: "jpg".to_string(),
+            quality: Some(70),
+            ..record()
+        };
+        assert_eq!(
+            render(OutputFormat::Template, Some("{format} q{quality}"), &jpeg).unwrap(),
+            "jpg q70"
+        );
+
+        let markdown = "![cover]({url})";
+        assert_eq!(
+            render(OutputFormat::Template, Some(markdown), &record()).unwrap(),
+            "![cover](https://i.imgur.com/abc.png)"
+        );
+    }
+
+    #[test]
+    fn test_validate_template() {
+        assert!(validate_template("{url} {sha256}").is_ok());
+        assert!(validate_template("no placeholders").is_ok());
+
+        for template in ["{link}", "{url", "url}", "{}"] {
+            assert!(matches!(
+                validate_template(template).unwrap_err(),
+                AppError::Config(_)
+            ));
+        }
+    }
+}
@@ -1,12 +1,14 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 use image::ImageFormat;
 use reqwest::multipart::{Form, Part};
 
 use crate::errors::AppError;
 use crate::image_processor::ProcessedImage;
-use crate::uploaders::UploadService;
+use crate::uploaders::{UploadResult, UploadService};
 
-use super::UploadServiceIdentifier;
+use super::{api_error, UploadServiceIdentifier};
 
 pub struct CatboxUploader;
 
@@ -14,15 +16,13 @@ pub struct CatboxUploader;
 impl UploadService for CatboxUploader {
     async fn upload(
         filename: String,
-        image: ProcessedImage,
+        image: &ProcessedImage,
         client_id: String,
-        user_agent: String,
-    ) -> Result<String, AppError> {
-        // the user agent is necessary
-        let client = reqwest::Client::builder().user_agent(user_agent).build()?;
-
+        endpoint: &str,
+        client: &reqwest::Client,
+    ) -> Result<UploadResult, AppError> {
         // Create the multipart form
-        let file_part = Part::stream(image.data)
+        let file_part = Part::stream(image.data.clone())
             .mime_str(image.format.to_mime_type())
             .map_err(|e| AppError::Upload(e.to_string()))?
             .file_name(filename);
@@ -33,23 +33,11 @@ impl UploadService for CatboxUploader {
             .part("fileToUpload", file_part);
 
         // Make the request to Catbox API
-        let response = client
-            .post("https://catbox.moe/user/api.php")
-            .multipart(form)
-            .send()
-            .await?;
+        let response = client.post(endpoint).multipart(form).send().await?;
 
         // Check if the request was successful
         if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-
-            return Err(AppError::Upload(format!(
-                "Catbox API error: {}",
-                error_text
-            )));
+            return Err(api_error(Self::identifier(), response).await);
         }
 
         // Get the URL from response (Catbox returns just the URL as text)
@@ -59,14 +47,35 @@ impl UploadService for CatboxUploader {
             return Err(AppError::Upload("Catbox returned invalid URL".to_string()));
         }
 
-        Ok(url)
+        // catbox has no delete token, files are removed through the account instead
+        Ok(UploadResult {
+            url,
+            service: Self::identifier(),
+            deletehash: None,
+        })
     }
 
     fn identifier() -> UploadServiceIdentifier {
         UploadServiceIdentifier::Catbox
     }
 
+    fn endpoint() -> &'static str {
+        "https://catbox.moe/user/api.php"
+    }
+
     fn formats() -> Vec<ImageFormat> {
-        vec![ImageFormat::Png, ImageFormat::WebP]
+        // catbox stores any image as is
+        vec![
+            ImageFormat::Png,
+            ImageFormat::WebP,
+            ImageFormat::Jpeg,
+            ImageFormat::Avif,
+            ImageFormat::Gif,
+        ]
+    }
+
+    fn cache_ttl() -> Duration {
+        // catbox files are permanent unless removed by moderation
+        Duration::from_secs(60 * 60 * 24 * 90)
     }
 }
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
 use image::ImageFormat;
 use reqwest::multipart::{Form, Part};
@@ -5,9 +7,9 @@ use reqwest::multipart::{Form, Part};
 use crate::errors::AppError;
 use crate::image_processor::ProcessedImage;
 use crate::models::imgur::ImgurResponse;
-use crate::uploaders::UploadService;
+use crate::uploaders::{UploadResult, UploadService};
 
-use super::UploadServiceIdentifier;
+use super::{api_error, UploadServiceIdentifier};
 
 pub struct ImgurUploader;
 
@@ -15,14 +17,13 @@ pub struct ImgurUploader;
 impl UploadService for ImgurUploader {
     async fn upload(
         filename: String,
-        image: ProcessedImage,
+        image: &ProcessedImage,
         client_id: String,
-        user_agent: String,
-    ) -> Result<String, AppError> {
-        let client = reqwest::Client::builder().user_agent(user_agent).build()?;
-
+        endpoint: &str,
+        client: &reqwest::Client,
+    ) -> Result<UploadResult, AppError> {
         // Create the multipart form
-        let part = Part::stream(image.data)
+        let part = Part::stream(image.data.clone())
             .mime_str(image.format.to_mime_type())
             .map_err(|e| AppError::Upload(e.to_string()))?
             .file_name(filename);
@@ -31,7 +32,7 @@ impl UploadService for ImgurUploader {
 
         // Make the request to Imgur API
         let response = client
-            .post("https://api.imgur.com/3/image")
+            .post(endpoint)
             .header("Authorization", format!("Client-ID {}", client_id))
             .multipart(form)
             .send()
@@ -39,12 +40,7 @@ impl UploadService for ImgurUploader {
 
         // Check if the request was successful
         if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-
-            return Err(AppError::Upload(format!("Imgur API error: {}", error_text)));
+            return Err(api_error(Self::identifier(), response).await);
         }
 
         // Parse the JSON response
@@ -57,14 +53,28 @@ impl UploadService for ImgurUploader {
             ));
         }
 
-        Ok(imgur_response.data.link)
+        Ok(UploadResult {
+            url: imgur_response.data.link,
+            service: Self::identifier(),
+            deletehash: imgur_response.data.deletehash,
+        })
     }
 
     fn identifier() -> UploadServiceIdentifier {
         UploadServiceIdentifier::Imgur
     }
 
+    fn endpoint() -> &'static str {
+        "https://api.imgur.com/3/image"
+    }
+
     fn formats() -> Vec<ImageFormat> {
-        vec![ImageFormat::Png]
+        // imgur turns WebP and AVIF uploads away
+        vec![ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif]
+    }
+
+    fn cache_ttl() -> Duration {
+        // anonymous uploads may be pruned, keep this fairly short
+        Duration::from_secs(60 * 60 * 24 * 30)
     }
 }
@@ -1,41 +1,137 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::{errors::AppError, image_processor::ProcessedImage};
+use retry::{retry_after, with_retry, RetryPolicy};
+
+use crate::{
+    errors::AppError,
+    image_processor::{can_animate, convert, ProcessedImage},
+};
 use async_trait::async_trait;
 use clap::ValueEnum;
 use image::ImageFormat;
+use serde::{Deserialize, Serialize};
 
 pub mod catbox;
 pub mod imgur;
+pub mod retry;
 
-#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
 pub enum UploadServiceIdentifier {
     Imgur,
     Catbox,
 }
 
 impl UploadServiceIdentifier {
-    pub fn to_string(&self) -> String {
+    pub fn formats(&self) -> Vec<ImageFormat> {
         match self {
-            UploadServiceIdentifier::Imgur => "imgur".to_string(),
-            UploadServiceIdentifier::Catbox => "catbox".to_string(),
+            UploadServiceIdentifier::Imgur => imgur::ImgurUploader::formats(),
+            UploadServiceIdentifier::Catbox => catbox::CatboxUploader::formats(),
         }
     }
 
-    pub fn formats(&self) -> Vec<ImageFormat> {
+    pub fn cache_ttl(&self) -> Duration {
         match self {
-            UploadServiceIdentifier::Imgur => imgur::ImgurUploader::formats(),
-            UploadServiceIdentifier::Catbox => catbox::CatboxUploader::formats(),
+            UploadServiceIdentifier::Imgur => imgur::ImgurUploader::cache_ttl(),
+            UploadServiceIdentifier::Catbox => catbox::CatboxUploader::cache_ttl(),
+        }
+    }
+}
+
+/// Limits for how long network operations may take
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Establishing a connection
+    pub connect: Duration,
+    /// A single request, from sending it to reading the whole response
+    pub request: Duration,
+    /// Everything the run does, including retries and fallback services
+    pub total: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            total: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Runs `run` within the total deadline, failing with a timeout when it is still busy then
+    pub async fn bound<T>(
+        &self,
+        run: impl Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        tokio::time::timeout(self.total, run)
+            .await
+            .map_err(|_| AppError::Timeout(format!("no result after {}s", self.total.as_secs())))?
+    }
+}
+
+/// Runs CPU-bound `work` on the blocking thread pool.
+///
+/// A timer is only checked when its task is polled, so decoding and encoding on the async
+/// worker would keep the total deadline from firing until they are done.
+pub async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        // the task is never aborted, so it can only have panicked
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Builds the client shared by all requests of a run
+pub fn http_client(user_agent: &str, timeouts: &Timeouts) -> Result<reqwest::Client, AppError> {
+    // the user agent is necessary for catbox
+    Ok(reqwest::Client::builder()
+        .user_agent(user_agent)
+        .connect_timeout(timeouts.connect)
+        .timeout(timeouts.request)
+        .build()?)
+}
+
+/// A service of the fallback chain together with its settings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UploadTarget {
+    pub service: UploadServiceIdentifier,
+    /// Client id for imgur, user hash for catbox, may be empty
+    pub client_id: String,
+    /// Replaces the service's own upload url
+    pub endpoint: Option<String>,
+    /// Format to re-encode to, instead of keeping the thumbnail's when the service accepts it
+    pub format: Option<ImageFormat>,
+}
+
+impl UploadTarget {
+    pub fn new(service: UploadServiceIdentifier) -> Self {
+        UploadTarget {
+            service,
+            client_id: String::new(),
+            endpoint: None,
+            format: None,
         }
     }
 }
 
+/// What a service returned for a successful upload
+pub struct UploadResult {
+    pub url: String,
+    pub service: UploadServiceIdentifier,
+    /// Token that allows deleting the upload, for services that provide one
+    pub deletehash: Option<String>,
+}
+
 pub async fn upload(
-    service: UploadServiceIdentifier,
-    image: ProcessedImage,
-    client_id: String,
-    user_agent: String,
-) -> Result<String, AppError> {
+    target: &UploadTarget,
+    image: &ProcessedImage,
+    client: &reqwest::Client,
+) -> Result<UploadResult, AppError> {
     // random filename
     let filename = format!(
         "{}.{}",
@@ -45,26 +141,274 @@ pub async fn upload(
             .as_micros(),
         image.format.extensions_str().first().unwrap()
     );
-    match service {
+    let client_id = target.client_id.clone();
+    let result = match target.service {
         UploadServiceIdentifier::Imgur => {
-            imgur::ImgurUploader::upload(filename, image, client_id, user_agent).await
+            let endpoint = target
+                .endpoint
+                .as_deref()
+                .unwrap_or(imgur::ImgurUploader::endpoint());
+            imgur::ImgurUploader::upload(filename, image, client_id, endpoint, client).await
         }
         UploadServiceIdentifier::Catbox => {
-            catbox::CatboxUploader::upload(filename, image, client_id, user_agent).await
+            let endpoint = target
+                .endpoint
+                .as_deref()
+                .unwrap_or(catbox::CatboxUploader::endpoint());
+            catbox::CatboxUploader::upload(filename, image, client_id, endpoint, client).await
+        }
+    };
+
+    // slow and rejected uploads are told apart
+    result.map_err(|e| match e {
+        AppError::Http(e) if e.is_timeout() => AppError::Timeout(e.to_string()),
+        e => e,
+    })
+}
+
+/// Tries each service in order until one accepts the image.
+///
+/// The thumbnail is re-encoded when a service does not accept its format, within the same size
+/// budget, and a service is skipped when the budget cannot be met in its format. Returns the image
+/// that was actually uploaded together with the result, or every failure if none succeeded.
+pub async fn upload_with_fallback(
+    targets: &[UploadTarget],
+    image: ProcessedImage,
+    client: &reqwest::Client,
+    retry: &RetryPolicy,
+) -> Result<(ProcessedImage, UploadResult), AppError> {
+    let mut failures = Vec::new();
+
+    for (i, target) in targets.iter().enumerate() {
+        let service = target.service;
+        let format = match (target.format, service.formats()) {
+            (Some(format), _) => format,
+            (None, formats) if formats.contains(&image.format) => image.format,
+            // an animation stays animated on hosts that take a format that can animate
+            (None, formats) => match formats.iter().find(|format| can_animate(**format)) {
+                Some(format) if image.animated => *format,
+                _ => formats[0],
+            },
+        };
+        let attempt = blocking({
+            let image = image.clone();
+            move || convert(&image, format)
+        })
+        .await;
+
+        let result = match attempt {
+            Ok(converted) => with_retry(retry, || upload(target, &converted, client))
+                .await
+                .map(|result| (converted, result)),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(uploaded) => return Ok(uploaded),
+            // a single service keeps its own error
+            Err(e) if targets.len() == 1 => return Err(e),
+            Err(e) => {
+                if let Some(next) = targets.get(i + 1) {
+                    eprintln!(
+                        "Warning: {} failed ({}), trying {}",
+                        service, e, next.service
+                    );
+                }
+                failures.push(format!("{}: {}", service, e));
+            }
         }
     }
+
+    Err(AppError::AllServicesFailed(failures))
+}
+
+/// Turns an unsuccessful response into an error that keeps the status and requested wait
+async fn api_error(service: UploadServiceIdentifier, response: reqwest::Response) -> AppError {
+    let status = response.status().as_u16();
+    let retry_after = retry_after(response.headers(), SystemTime::now());
+    let message = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unknown error".to_string());
+
+    AppError::Api {
+        service: service.to_string(),
+        status,
+        message,
+        retry_after,
+    }
 }
 
 #[async_trait]
 pub trait UploadService {
     async fn upload(
         filename: String,
-        image: ProcessedImage,
+        image: &ProcessedImage,
         client_id: String,
-        user_agent: String,
-    ) -> Result<String, AppError>;
+        endpoint: &str,
+        client: &reqwest::Client,
+    ) -> Result<UploadResult, AppError>;
 
     fn identifier() -> UploadServiceIdentifier;
 
+    /// Upload url used unless the config file sets another one
+    fn endpoint() -> &'static str;
+
     fn formats() -> Vec<ImageFormat>;
+
+    /// How long a cached link from this service is trusted without re-uploading
+    fn cache_ttl() -> Duration;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_processor::{create_thumbnail_from_memory, Encoding, ImageProcessingOptions};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Answers multipart uploads with the given raw HTTP responses, one per connection
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                // read up to the closing multipart boundary so the client is not reset
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                while !request.ends_with(b"--\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        url
+    }
+
+    fn image() -> ProcessedImage {
+        let mut data = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+
+        ProcessedImage {
+            data,
+            format: ImageFormat::Png,
+            encoding: Encoding::default(),
+            width: 4,
+            height: 4,
+            animated: false,
+            max_bytes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_to_configured_endpoint() {
+        let endpoint = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 32\r\nConnection: close\r\n\r\nhttps://files.catbox.moe/abc.png",
+        ]);
+
+        let target = UploadTarget {
+            endpoint: Some(format!("{}/user/api.php", endpoint)),
+            format: Some(ImageFormat::WebP),
+            ..UploadTarget::new(UploadServiceIdentifier::Catbox)
+        };
+
+        let (uploaded, result) = upload_with_fallback(
+            &[target],
+            image(),
+            &reqwest::Client::new(),
+            &RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.url, "https://files.catbox.moe/abc.png");
+        assert_eq!(result.service, UploadServiceIdentifier::Catbox);
+        // the target's format wins over the thumbnail's
+        assert_eq!(uploaded.format, ImageFormat::WebP);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_keeps_animation() {
+        let endpoint = serve(vec!["HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 62\r\nConnection: close\r\n\r\n{\"data\":{\"link\":\"https://i.imgur.com/abc.gif\"},\"success\":true}"]);
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            for shade in [0, 255] {
+                let frame = image::RgbaImage::from_pixel(4, 4, image::Rgba([shade, 0, 0, 255]));
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        let gif = ProcessedImage {
+            data: gif,
+            format: ImageFormat::Gif,
+            animated: true,
+            ..image()
+        };
+        let webp = convert(&gif, ImageFormat::WebP).unwrap();
+        assert!(webp.animated);
+
+        let target = UploadTarget {
+            client_id: "test_id".to_string(),
+            endpoint: Some(format!("{}/3/image", endpoint)),
+            ..UploadTarget::new(UploadServiceIdentifier::Imgur)
+        };
+
+        let (uploaded, result) = upload_with_fallback(
+            &[target],
+            webp,
+            &reqwest::Client::new(),
+            &RetryPolicy::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result.url, "https://i.imgur.com/abc.gif");
+        // imgur takes no webp, gif keeps the frames where png would not
+        assert_eq!(uploaded.format, ImageFormat::Gif);
+        assert!(uploaded.animated);
+    }
+
+    #[test]
+    fn test_total_timeout_during_processing() {
+        let mut rng = fastrand::Rng::with_seed(7);
+        let noise = image::RgbImage::from_fn(512, 512, |_, _| {
+            image::Rgb([rng.u8(..), rng.u8(..), rng.u8(..)])
+        });
+        let mut source = Vec::new();
+        image::DynamicImage::ImageRgb8(noise)
+            .write_to(&mut std::io::Cursor::new(&mut source), ImageFormat::Png)
+            .unwrap();
+
+        let timeouts = Timeouts {
+            total: Duration::from_millis(10),
+            ..Timeouts::default()
+        };
+        let options = ImageProcessingOptions {
+            format: ImageFormat::Avif,
+            ..Default::default()
+        };
+
+        // like main, the run does not wait for work that outlived the deadline
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let started = Instant::now();
+        let result = runtime.block_on(timeouts.bound(blocking(move || {
+            create_thumbnail_from_memory(&source, &options)
+        })));
+        let elapsed = started.elapsed();
+        runtime.shutdown_background();
+
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+        assert!(elapsed < Duration::from_secs(1));
+    }
 }
@@ -0,0 +1,265 @@
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use crate::errors::AppError;
+
+/// How often and how patiently a failed upload is retried
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub base_delay: Duration,
+    /// Upper bound for a single wait, servers asking for more are not retried
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for the given retry (0 based), `jitter` in `0.0..1.0` spreads
+    /// the second half of the delay so parallel clients do not retry in lockstep
+    pub fn backoff(&self, retry: u32, jitter: f64) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+
+        delay / 2 + (delay / 2).mul_f64(jitter.clamp(0.0, 1.0))
+    }
+}
+
+/// Whether an upload error is worth another attempt
+pub fn is_retryable(error: &AppError) -> bool {
+    match error {
+        AppError::Api { status, .. } => {
+            let status = StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_REQUEST);
+            status.is_server_error()
+                || status == StatusCode::TOO_MANY_REQUESTS
+                || status == StatusCode::REQUEST_TIMEOUT
+        }
+        // connection resets, refused connections and timeouts
+        AppError::Http(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+        AppError::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+/// Reads how long the server wants us to wait, from `Retry-After` or imgur's rate limit headers
+pub fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+
+    if let Some(value) = header(RETRY_AFTER.as_str()) {
+        // either delay-seconds or an HTTP-date
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(date.duration_since(now).unwrap_or_default());
+        }
+    }
+
+    let exhausted = |name: &str| header(name).and_then(|v| v.parse::<u64>().ok()) == Some(0);
+
+    // imgur: seconds until the post limit resets
+    if exhausted("X-Post-Rate-Limit-Remaining") {
+        if let Some(seconds) = header("X-Post-Rate-Limit-Reset").and_then(|v| v.parse().ok()) {
+            return Some(Duration::from_secs(seconds));
+        }
+    }
+
+    // imgur: unix timestamp at which the user limit resets
+    if exhausted("X-RateLimit-UserRemaining") {
+        if let Some(reset) = header("X-RateLimit-UserReset").and_then(|v| v.parse().ok()) {
+            let reset = SystemTime::UNIX_EPOCH + Duration::from_secs(reset);
+            return Some(reset.duration_since(now).unwrap_or_default());
+        }
+    }
+
+    None
+}
+
+/// Runs `attempt` until it succeeds, fails with a non-retryable error or runs out of retries
+pub async fn with_retry<T, F, Fut>(policy: &RetryPolicy, mut attempt: F) -> Result<T, AppError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let mut retry = 0;
+
+    loop {
+        let error = match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        if retry >= policy.retries || !is_retryable(&error) {
+            return Err(error);
+        }
+
+        let delay = match &error {
+            AppError::Api {
+                retry_after: Some(requested),
+                ..
+            } => {
+                // waiting longer than allowed is pointless, let the next service have a go
+                if *requested > policy.max_delay {
+                    return Err(error);
+                }
+                *requested
+            }
+            _ => policy.backoff(retry, fastrand::f64()),
+        };
+
+        eprintln!(
+            "Warning: {}, retrying in {:.1}s",
+            error,
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::cell::Cell;
+
+    fn api_error(status: u16, retry_after: Option<Duration>) -> AppError {
+        AppError::Api {
+            service: "catbox".to_string(),
+            status,
+            message: "error".to_string(),
+            retry_after,
+        }
+    }
+
+    fn fast_policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.backoff(0, 1.0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(1, 1.0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(10, 1.0), Duration::from_secs(30));
+        assert_eq!(policy.backoff(100, 1.0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(&api_error(500, None)));
+        assert!(is_retryable(&api_error(503, None)));
+        assert!(is_retryable(&api_error(429, None)));
+        assert!(!is_retryable(&api_error(400, None)));
+        assert!(!is_retryable(&api_error(401, None)));
+        assert!(!is_retryable(&api_error(403, None)));
+        assert!(is_retryable(&AppError::Timeout("slow".to_string())));
+        assert!(!is_retryable(&AppError::Upload("invalid".to_string())));
+    }
+
+    #[test]
+    fn test_retry_after_headers() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(120)));
+
+        let date = httpdate::fmt_http_date(now + Duration::from_secs(30));
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(30)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Post-Rate-Limit-Remaining", HeaderValue::from_static("0"));
+        headers.insert("X-Post-Rate-Limit-Reset", HeaderValue::from_static("45"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(45)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-UserRemaining", HeaderValue::from_static("0"));
+        headers.insert("X-RateLimit-UserReset", HeaderValue::from_static("1000060"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(60)));
+
+        // limits that are not exhausted do not delay anything
+        headers.insert("X-RateLimit-UserRemaining", HeaderValue::from_static("10"));
+        assert_eq!(retry_after(&headers, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_recovers_from_server_errors() {
+        let attempts = Cell::new(0);
+
+        let result = with_retry(&fast_policy(3), || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                match attempt {
+                    1 | 2 => Err(api_error(502, None)),
+                    _ => Ok("https://files.catbox.moe/abc.png"),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), "https://files.catbox.moe/abc.png");
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_with_retry_gives_up() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retry(&fast_policy(2), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(api_error(500, None)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // auth failures are never retried
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retry(&fast_policy(2), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(api_error(401, None)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        // the server asks for a longer wait than allowed
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retry(&fast_policy(2), || {
+            attempts.set(attempts.get() + 1);
+            async { Err(api_error(429, Some(Duration::from_secs(3600)))) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -1,9 +1,7 @@
 use clap::Parser;
 use image::{GenericImageView, ImageBuffer, Rgb};
 use rich_thumbnail_uploader::{
-    cli::Cli,
-    config::Config,
-    image_processor::{create_thumbnail, ImageProcessingOptions},
+    cli::Cli, config::Config, config_file::ConfigFile, image_processor::create_thumbnail,
 };
 use std::path::PathBuf;
 use tempfile::TempDir;
@@ -42,13 +40,10 @@ fn test_end_to_end_image_processing() {
     let cli = Cli::parse_from(args);
 
     // Initialize config
-    let config = Config::new(&cli).unwrap();
+    let config = Config::from_sources(&cli, &ConfigFile::default(), |_| None).unwrap();
 
     // Process image
-    let options = ImageProcessingOptions {
-        size: config.image_dimensions.0,
-        format: config.image_format.to_image_format(),
-    };
+    let options = config.processing_options();
 
     let result = create_thumbnail(file_path.to_str().unwrap(), &options).unwrap();
 
@@ -71,7 +66,7 @@ fn test_config_validation() {
         "png",
     ];
     let cli = Cli::parse_from(args);
-    let config = Config::new(&cli);
+    let config = Config::from_sources(&cli, &ConfigFile::default(), |_| None);
     assert!(config.is_ok());
 
     // Test invalid format for service
@@ -85,6 +80,6 @@ fn test_config_validation() {
         "test_id",
     ];
     let cli = Cli::parse_from(args);
-    let config = Config::new(&cli);
+    let config = Config::from_sources(&cli, &ConfigFile::default(), |_| None);
     assert!(config.is_err());
 }